@@ -0,0 +1,2 @@
+-- Optional display name used to greet the user in their digest.
+ALTER TABLE users ADD COLUMN name STRING;
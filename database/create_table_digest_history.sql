@@ -0,0 +1,6 @@
+CREATE TABLE digest_history (
+    show_id       int4 not null,
+    show_name     STRING not null,
+    episode_name  STRING not null,
+    airstamp      STRING not null
+);
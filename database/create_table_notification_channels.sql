@@ -0,0 +1,11 @@
+CREATE TABLE notification_channels (
+    user_id       STRING not null REFERENCES users (id),
+    channel_type  STRING not null,
+    destination   STRING not null,
+    enabled       BOOL not null DEFAULT true,
+    CONSTRAINT pk PRIMARY KEY(user_id, channel_type, destination)
+);
+
+-- Existing subscribers keep receiving the digest at their users.email address.
+INSERT INTO notification_channels (user_id, channel_type, destination)
+SELECT id, 'email', email FROM users WHERE email IS NOT NULL;
@@ -0,0 +1,194 @@
+use reqwest::StatusCode;
+use std::error::Error;
+use std::fmt;
+use std::fmt::Formatter;
+use tokio_postgres::error::SqlState;
+
+/// An error from one stage of a run, categorized by the subsystem that failed.
+#[derive(Debug)]
+pub enum AppError {
+    Config(String),
+    Database(String),
+    Http(String),
+    Parse(String),
+    Smtp(String),
+    /// A timeout, dropped connection or overloaded upstream that may clear up on its own; keeps
+    /// the category of the wrapped error.
+    Transient(Box<AppError>),
+}
+
+impl AppError {
+    /// A short, stable name for the category, used as a metrics label.
+    pub fn category(&self) -> &'static str {
+        match self {
+            AppError::Config(_) => "config",
+            AppError::Database(_) => "database",
+            AppError::Http(_) => "http",
+            AppError::Parse(_) => "parse",
+            AppError::Smtp(_) => "smtp",
+            AppError::Transient(err) => err.category(),
+        }
+    }
+
+    /// The process exit code for a run that failed with this error, so monitoring can tell
+    /// categories apart: 2 config, 3 database, 4 http, 5 smtp, 6 parse.
+    ///
+    /// Code 1 is left for dry runs that completed but found a problem.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            AppError::Config(_) => 2,
+            AppError::Database(_) => 3,
+            AppError::Http(_) => 4,
+            AppError::Smtp(_) => 5,
+            AppError::Parse(_) => 6,
+            AppError::Transient(err) => err.exit_code(),
+        }
+    }
+
+    /// Whether retrying the same work later might succeed, e.g. after a timeout rather than an
+    /// authentication failure or a 404.
+    pub fn is_transient(&self) -> bool {
+        matches!(self, AppError::Transient(_))
+    }
+
+    /// Marks the error as one that retrying might get past.
+    pub fn transient(self) -> Self {
+        match self {
+            AppError::Transient(_) => self,
+            err => AppError::Transient(Box::new(err)),
+        }
+    }
+
+    /// Prefixes the message with `context`, keeping the category.
+    pub fn context(self, context: &str) -> Self {
+        match self {
+            AppError::Config(msg) => AppError::Config(format!("{context}: {msg}")),
+            AppError::Database(msg) => AppError::Database(format!("{context}: {msg}")),
+            AppError::Http(msg) => AppError::Http(format!("{context}: {msg}")),
+            AppError::Parse(msg) => AppError::Parse(format!("{context}: {msg}")),
+            AppError::Smtp(msg) => AppError::Smtp(format!("{context}: {msg}")),
+            AppError::Transient(err) => AppError::Transient(Box::new(err.context(context))),
+        }
+    }
+
+    fn message(&self) -> &str {
+        match self {
+            AppError::Config(msg)
+            | AppError::Database(msg)
+            | AppError::Http(msg)
+            | AppError::Parse(msg)
+            | AppError::Smtp(msg) => msg,
+            AppError::Transient(err) => err.message(),
+        }
+    }
+}
+
+impl fmt::Display for AppError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{} error: {}", self.category(), self.message())
+    }
+}
+
+impl Error for AppError {}
+
+/// Connection failures, timeouts, rate limiting and server errors are worth retrying.
+pub fn is_transient_request(err: &reqwest::Error) -> bool {
+    match err.status() {
+        Some(status) => status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS,
+        None => err.is_timeout() || err.is_connect() || err.is_request(),
+    }
+}
+
+/// Lost or refused connections and server-side connection and serialization failures are
+/// worth retrying; authentication and query errors are not.
+fn is_transient_postgres(err: &tokio_postgres::Error) -> bool {
+    if let Some(code) = err.code() {
+        return code.code().starts_with("08")
+            || [
+                SqlState::T_R_SERIALIZATION_FAILURE,
+                SqlState::ADMIN_SHUTDOWN,
+                SqlState::CANNOT_CONNECT_NOW,
+                SqlState::TOO_MANY_CONNECTIONS,
+            ]
+            .contains(code);
+    }
+    err.is_closed()
+        || err
+            .source()
+            .is_some_and(|source| source.is::<std::io::Error>())
+}
+
+impl From<tokio_postgres::Error> for AppError {
+    fn from(err: tokio_postgres::Error) -> Self {
+        let transient = is_transient_postgres(&err);
+        let err = AppError::Database(err.to_string());
+        if transient {
+            err.transient()
+        } else {
+            err
+        }
+    }
+}
+
+impl From<openssl::error::ErrorStack> for AppError {
+    fn from(err: openssl::error::ErrorStack) -> Self {
+        AppError::Database(format!("tls setup failed: {err}"))
+    }
+}
+
+impl From<rusqlite::Error> for AppError {
+    fn from(err: rusqlite::Error) -> Self {
+        let busy = matches!(
+            err.sqlite_error_code(),
+            Some(rusqlite::ErrorCode::DatabaseBusy | rusqlite::ErrorCode::DatabaseLocked)
+        );
+        let err = AppError::Database(err.to_string());
+        if busy {
+            err.transient()
+        } else {
+            err
+        }
+    }
+}
+
+impl From<reqwest::Error> for AppError {
+    fn from(err: reqwest::Error) -> Self {
+        let transient = is_transient_request(&err);
+        let err = AppError::Http(err.to_string());
+        if transient {
+            err.transient()
+        } else {
+            err
+        }
+    }
+}
+
+impl From<serde_json::Error> for AppError {
+    fn from(err: serde_json::Error) -> Self {
+        AppError::Parse(err.to_string())
+    }
+}
+
+impl From<lettre::address::AddressError> for AppError {
+    fn from(err: lettre::address::AddressError) -> Self {
+        AppError::Config(format!("invalid email address: {err}"))
+    }
+}
+
+impl From<lettre::error::Error> for AppError {
+    fn from(err: lettre::error::Error) -> Self {
+        AppError::Smtp(err.to_string())
+    }
+}
+
+impl From<lettre::transport::smtp::Error> for AppError {
+    fn from(err: lettre::transport::smtp::Error) -> Self {
+        AppError::Smtp(err.to_string())
+    }
+}
+
+impl From<lettre::transport::file::Error> for AppError {
+    fn from(err: lettre::transport::file::Error) -> Self {
+        AppError::Smtp(err.to_string())
+    }
+}
@@ -0,0 +1,90 @@
+use serde::{Deserialize, Serialize};
+
+/// The language the digest is written in.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Locale {
+    #[default]
+    En,
+    De,
+}
+
+impl Locale {
+    pub fn messages(self) -> &'static Messages {
+        match self {
+            Locale::En => &EN,
+            Locale::De => &DE,
+        }
+    }
+}
+
+/// Every piece of digest text, with `{}` marking where a value goes.
+pub struct Messages {
+    /// Used for the day and weekday names in dates.
+    pub chrono_locale: chrono::Locale,
+    pub date_time_format: &'static str,
+    pub date_format: &'static str,
+    pub daily_subject: &'static str,
+    pub weekly_subject: &'static str,
+    pub named_greeting: &'static str,
+    pub greeting: &'static str,
+    pub today_heading: &'static str,
+    pub nothing_today: &'static str,
+    pub future_heading: &'static str,
+    pub week_heading: &'static str,
+    pub nothing_this_week: &'static str,
+    pub more: &'static str,
+    pub recently_aired_heading: &'static str,
+    pub removed_heading: &'static str,
+    pub stalled_heading: &'static str,
+    pub new_badge: &'static str,
+    pub finale_badge: &'static str,
+    pub rescheduled_note: &'static str,
+    pub manage_subscriptions: &'static str,
+}
+
+pub const EN: Messages = Messages {
+    chrono_locale: chrono::Locale::en_US,
+    date_time_format: "%a. %b. %d %l:%M %p",
+    date_format: "%a. %b. %d",
+    daily_subject: "Upcoming shows for {}",
+    weekly_subject: "This week in TV: {}",
+    named_greeting: "Hi {},",
+    greeting: "Hi there,",
+    today_heading: "Today's shows:",
+    nothing_today: "Nothing airing today.",
+    future_heading: "Future shows:",
+    week_heading: "This week in TV:",
+    nothing_this_week: "Nothing airing this week.",
+    more: "+{} more",
+    recently_aired_heading: "Recently aired:",
+    removed_heading: "No longer scheduled:",
+    stalled_heading: "No upcoming episodes:",
+    new_badge: "NEW",
+    finale_badge: "Season Finale",
+    rescheduled_note: "rescheduled (was {})",
+    manage_subscriptions: "Manage subscriptions on",
+};
+
+pub const DE: Messages = Messages {
+    chrono_locale: chrono::Locale::de_DE,
+    date_time_format: "%a. %d. %b. %H:%M",
+    date_format: "%a. %d. %b.",
+    daily_subject: "Kommende Sendungen ab {}",
+    weekly_subject: "Diese Woche im TV: {}",
+    named_greeting: "Hallo {},",
+    greeting: "Hallo,",
+    today_heading: "Heute:",
+    nothing_today: "Heute läuft nichts.",
+    future_heading: "Demnächst:",
+    week_heading: "Diese Woche im TV:",
+    nothing_this_week: "Diese Woche läuft nichts.",
+    more: "+{} weitere",
+    recently_aired_heading: "Kürzlich ausgestrahlt:",
+    removed_heading: "Nicht mehr geplant:",
+    stalled_heading: "Keine neuen Folgen:",
+    new_badge: "NEU",
+    finale_badge: "Staffelfinale",
+    rescheduled_note: "verschoben (vorher {})",
+    manage_subscriptions: "Abonnements verwalten auf",
+};
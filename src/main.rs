@@ -1,31 +1,280 @@
-use chrono::{DateTime, Days, Local};
+use chrono::{DateTime, Days, FixedOffset, Local, NaiveDate, NaiveTime};
+use error::AppError;
 use lettre::{
-    message::SinglePart, transport::smtp::authentication::Credentials, Message, SmtpTransport,
-    Transport,
+    message::{Mailbox, SinglePart},
+    transport::smtp::authentication::Credentials,
+    FileTransport, Message, SmtpTransport, Transport,
 };
-use openssl::ssl::{SslConnector, SslMethod};
-use postgres_openssl::MakeTlsConnector;
+use locale::{Locale, Messages};
+use rand::{Rng, RngExt};
+use reqwest::header::CONTENT_TYPE;
 use serde::{Deserialize, Serialize};
 use serde_json::{Map, Value};
-use std::error::Error;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fmt;
 use std::fmt::Formatter;
 use std::fs;
-use tokio_postgres::{self};
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::str::FromStr;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use store::{DigestEntry, NotificationChannel};
+
+mod config_template;
+mod error;
+mod locale;
+mod metrics;
+mod server;
+mod store;
 
 const DATE_TIME_FORMAT: &str = "%a. %b. %d %l:%M %p";
-const DATE_FORMAT: &str = "%a. %b. %d";
 const FUTURE_DAY_LIMIT: u64 = 7;
+/// Exit code of a dry run in which some shows could not be fetched.
+const EXIT_FETCH_FAILURES: i32 = 1;
+/// Exit code of a `--fail-on-empty` dry run that found no shows.
+const EXIT_EMPTY: i32 = 7;
+const DEFAULT_PRUNE_MAX_AGE_DAYS: u64 = 90;
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 struct Config {
+    #[serde(default)]
     pg_connection_string: String,
+    #[serde(default)]
+    database_url: Option<String>,
     smtp_server: String,
     smtp_host: String,
     smtp_user: String,
     smtp_password: String,
     from_email: String,
     site_url: String,
+    #[serde(default)]
+    skip_aired: bool,
+    #[serde(default)]
+    serve_address: Option<String>,
+    #[serde(default)]
+    serve_secret: Option<String>,
+    #[serde(default)]
+    pushgateway_url: Option<String>,
+    #[serde(default)]
+    from_name: Option<String>,
+    #[serde(default)]
+    reply_to: Option<String>,
+    #[serde(default)]
+    show_priorities: HashMap<i32, i32>,
+    #[serde(default)]
+    show_stalled: bool,
+    #[serde(default)]
+    abort_on_fetch_failure: bool,
+    #[serde(default = "default_fetch_retries")]
+    fetch_retries: u32,
+    #[serde(default = "default_retry_base_delay_ms")]
+    retry_base_delay_ms: u64,
+    #[serde(default)]
+    quiet_hours: Option<QuietHours>,
+    #[serde(default)]
+    lookback_days: u64,
+    /// List every episode airing within the next week instead of only the next one.
+    #[serde(default)]
+    all_episodes: bool,
+    #[serde(default)]
+    layout: Layout,
+    #[serde(default)]
+    sort_order: SortOrder,
+    #[serde(default)]
+    group_by: GroupBy,
+    /// When set, digests are written as `.eml` files into this directory instead of being relayed.
+    #[serde(default)]
+    outbox_dir: Option<PathBuf>,
+    #[serde(default)]
+    fetch_strategy: FetchStrategy,
+    /// ISO country code of the broadcast schedule used by the `schedule` strategy.
+    #[serde(default = "default_schedule_country")]
+    schedule_country: String,
+    /// Compare each digest with the previous one sent, marking new episodes and dropped ones.
+    #[serde(default)]
+    highlight_changes: bool,
+    /// Note episodes whose air time moved since the last digest; needs `highlight_changes`,
+    /// which keeps the digest history.
+    #[serde(default)]
+    highlight_reschedules: bool,
+    /// Badge season finales, at the cost of one extra TVMaze request per listed show.
+    #[serde(default)]
+    highlight_finales: bool,
+    /// Bound on connecting to Postgres and on each query, in seconds.
+    #[serde(default = "default_db_timeout_secs")]
+    db_timeout_secs: u64,
+    /// Per-user link to manage subscriptions, with `{user_id}` replaced by the percent-encoded
+    /// id; defaults to `site_url`.
+    #[serde(default)]
+    manage_url_template: Option<String>,
+    /// Don't send a digest that would list no episodes at all.
+    #[serde(default)]
+    skip_empty: bool,
+    /// How many times a run that failed transiently (timeouts, dropped connections, upstream
+    /// 5xx/429) is started over.
+    #[serde(default)]
+    run_retries: u32,
+    #[serde(default = "default_run_retry_delay_secs")]
+    run_retry_delay_secs: u64,
+    /// Receives a JSON summary of every run, successful or not.
+    #[serde(default)]
+    completion_webhook_url: Option<String>,
+    #[serde(default)]
+    locale: Locale,
+    /// Every send attempt is appended here as a JSON line.
+    #[serde(default)]
+    audit_log_path: Option<PathBuf>,
+    /// Relays tried in order until one accepts the message; when empty, `smtp_host` with
+    /// `smtp_user`/`smtp_password` is the only relay.
+    #[serde(default)]
+    smtp_relays: Vec<SmtpRelay>,
+    /// Sent with every outbound HTTP request; defaults to `tvnotifier/<version> (+<site_url>)`.
+    #[serde(default)]
+    user_agent: Option<String>,
+    /// Bound on connecting for, and completing, each outbound HTTP request, including the
+    /// completion webhook, in seconds.
+    #[serde(default = "default_http_timeout_secs")]
+    http_timeout_secs: u64,
+    /// How many users' digests are sent at once.
+    #[serde(default = "default_send_concurrency")]
+    send_concurrency: usize,
+}
+
+/// One SMTP relay to deliver through.
+#[derive(Clone, Serialize, Deserialize)]
+struct SmtpRelay {
+    host: String,
+    /// Defaults to the standard port for `encryption`.
+    #[serde(default)]
+    port: Option<u16>,
+    #[serde(default)]
+    encryption: SmtpEncryption,
+    #[serde(default)]
+    user: String,
+    #[serde(default)]
+    password: String,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum SmtpEncryption {
+    /// Implicit TLS, port 465.
+    #[default]
+    Tls,
+    /// Upgrade a plain connection with STARTTLS, port 587.
+    Starttls,
+    /// Unencrypted, port 25; only for relays on a trusted network.
+    None,
+}
+
+/// How tracked shows are looked up on TVMaze.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum FetchStrategy {
+    /// One request per tracked show.
+    #[default]
+    PerShow,
+    /// A couple of requests per day of the window, filtered down to tracked shows.
+    Schedule,
+}
+
+fn default_fetch_retries() -> u32 {
+    2
+}
+
+fn default_retry_base_delay_ms() -> u64 {
+    500
+}
+
+fn default_db_timeout_secs() -> u64 {
+    30
+}
+
+fn default_run_retry_delay_secs() -> u64 {
+    30
+}
+
+fn default_http_timeout_secs() -> u64 {
+    10
+}
+
+fn default_send_concurrency() -> usize {
+    4
+}
+
+fn default_schedule_country() -> String {
+    "US".to_owned()
+}
+
+/// A local-time window, written as `"HH:MM"`, during which no digest is sent.
+///
+/// A window whose end is before its start wraps past midnight, e.g. 22:00 to 07:00.
+#[derive(Clone, Serialize, Deserialize)]
+struct QuietHours {
+    #[serde(with = "hour_minute")]
+    start: NaiveTime,
+    #[serde(with = "hour_minute")]
+    end: NaiveTime,
+}
+
+impl QuietHours {
+    fn contains(&self, time: NaiveTime) -> bool {
+        if self.start <= self.end {
+            self.start <= time && time < self.end
+        } else {
+            time >= self.start || time < self.end
+        }
+    }
+}
+
+mod hour_minute {
+    use chrono::NaiveTime;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    const FORMAT: &str = "%H:%M";
+
+    pub fn serialize<S: Serializer>(time: &NaiveTime, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&time.format(FORMAT).to_string())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<NaiveTime, D::Error> {
+        let time = String::deserialize(deserializer)?;
+        NaiveTime::parse_from_str(&time, FORMAT).map_err(serde::de::Error::custom)
+    }
+}
+
+/// Options shared by every show fetch in a run.
+#[derive(Clone, Copy)]
+struct FetchOptions {
+    /// The reference time for deciding what counts as "today" and "already aired".
+    now: DateTime<Local>,
+    /// Drop today's episodes whose air time is already in the past.
+    skip_aired: bool,
+    /// How many times a transient TVMaze failure is retried before giving up.
+    retries: u32,
+    /// The backoff before retry `n` is drawn uniformly from `0..=retry_base_delay * 2^n`.
+    retry_base_delay: Duration,
+    /// How many days before today an aired episode still counts as recently aired.
+    lookback_days: u64,
+    /// Fetch each show's full episode list so every airing in the window is reported.
+    all_episodes: bool,
+}
+
+impl FetchOptions {
+    fn new(config: &Config, options: RunOptions) -> Self {
+        FetchOptions {
+            now: Local::now(),
+            skip_aired: options.skip_aired,
+            retries: config.fetch_retries,
+            retry_base_delay: Duration::from_millis(config.retry_base_delay_ms),
+            lookback_days: config.lookback_days,
+            all_episodes: config.all_episodes,
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -34,237 +283,2895 @@ struct Show {
     name: String,
     episode_name: String,
     show_time: DateTime<chrono::Local>,
+    image: Option<String>,
+    /// Episode length in minutes, when TVMaze knows it.
+    runtime: Option<i32>,
+    /// Not in the previous digest; only set when `highlight_changes` is on.
+    new: bool,
+    season: Option<i32>,
+    /// Episode number within `season`.
+    number: Option<i32>,
+    /// The last episode of its season, when TVMaze knows the season's length; only set when
+    /// `highlight_finales` is on.
+    finale: bool,
+    /// The air time listed in the previous digest, when it has changed since; only set when
+    /// `highlight_reschedules` is on.
+    rescheduled_from: Option<DateTime<Local>>,
+}
+
+/// A tracked show with nothing scheduled, e.g. because it ended or is on hiatus.
+#[derive(Debug)]
+struct StalledShow {
+    id: i32,
+    name: String,
+}
+
+/// What TVMaze has next for a tracked show.
+enum EpisodeLookup {
+    Scheduled(Show),
+    /// The previous episode aired within the configured lookback window, before today.
+    RecentlyAired(Show),
+    Stalled(StalledShow),
+}
+
+/// A tracked show whose lookup failed.
+#[derive(Debug)]
+struct FetchFailure {
+    show_id: i32,
+    error: AppError,
+}
+
+/// The shows fetched in one run, ready to render.
+struct Digest {
+    /// Sorted by air time.
+    shows: Vec<Show>,
+    /// Episodes from the last `lookback_days` days, sorted by air time.
+    recently_aired: Vec<Show>,
+    stalled: Vec<StalledShow>,
+    failures: Vec<FetchFailure>,
+    /// Upcoming episodes from the previous digest that are no longer listed.
+    removed: Vec<DigestEntry>,
 }
 
 impl fmt::Display for Show {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         write!(
             f,
-            "{}: {} ({})",
+            "{}: {} ({}){}{}",
             self.show_time.format(DATE_TIME_FORMAT),
             self.name,
-            self.episode_name
-        )
+            self.episode_name,
+            self.runtime_suffix(),
+            if self.finale { " - Season Finale" } else { "" },
+        )?;
+        if let Some(previous_time) = self.rescheduled_from {
+            write!(
+                f,
+                " - rescheduled (was {})",
+                previous_time.format(DATE_TIME_FORMAT)
+            )?;
+        }
+        Ok(())
     }
 }
 
 impl Show {
-    fn html(&self) -> String {
+    fn html(&self, messages: &Messages) -> String {
+        let thumbnail = match &self.image {
+            Some(image) => format!(
+                "<img src=\"{}\" alt=\"\" height=\"40\" style=\"vertical-align: middle\" /> ",
+                escape_html(image)
+            ),
+            None => "".to_owned(),
+        };
+        let rescheduled = match self.rescheduled_from {
+            Some(previous_time) => format!(
+                " <i>{}</i>",
+                messages.rescheduled_note.replace(
+                    "{}",
+                    &previous_time
+                        .format_localized(messages.date_time_format, messages.chrono_locale)
+                        .to_string()
+                )
+            ),
+            None => "".to_owned(),
+        };
         format!(
-            "{}: <a href=\"https://www.tvmaze.com/shows/{}\">{}</a> ({})",
-            self.show_time.format(DATE_TIME_FORMAT),
+            "{}{}: <a href=\"https://www.tvmaze.com/shows/{}\">{}</a> ({}){}{}{}{}",
+            thumbnail,
+            self.show_time
+                .format_localized(messages.date_time_format, messages.chrono_locale),
             self.id,
-            self.name,
-            self.episode_name,
+            escape_html(&self.name),
+            escape_html(&self.episode_name),
+            self.runtime_suffix(),
+            badge(self.new, messages.new_badge),
+            badge(self.finale, messages.finale_badge),
+            rescheduled,
         )
     }
+
+    fn runtime_suffix(&self) -> String {
+        match self.runtime {
+            Some(minutes) => format!(" ({minutes} min)"),
+            None => "".to_owned(),
+        }
+    }
+}
+
+#[cfg(test)]
+impl Show {
+    /// A show named `name` airing at `show_time`, with every optional detail unset.
+    ///
+    /// Override other fields with struct update syntax, e.g. `Show { runtime: Some(45), ..Show::test(..) }`.
+    fn test(name: &str, show_time: DateTime<Local>) -> Show {
+        Show {
+            id: 1,
+            name: name.to_owned(),
+            episode_name: "Episode".to_owned(),
+            show_time,
+            image: None,
+            runtime: None,
+            new: false,
+            season: None,
+            number: None,
+            finale: false,
+            rescheduled_from: None,
+        }
+    }
+}
+
+fn badge(shown: bool, label: &str) -> String {
+    if shown {
+        format!(" <b>{label}</b>")
+    } else {
+        "".to_owned()
+    }
+}
+
+/// Flags that shape a single fetch-and-send run.
+#[derive(Clone, Copy)]
+struct RunOptions {
+    skip_aired: bool,
+    no_mail: bool,
+    /// Cap on the number of future shows in the digest.
+    limit: Option<usize>,
+    /// List shows with no upcoming episode in their own section.
+    show_stalled: bool,
+    /// Treat a dry run that finds no shows as a failure.
+    fail_on_empty: bool,
+    mode: DigestMode,
+}
+
+/// Presentation settings for one rendering of the digest.
+#[derive(Clone, Copy)]
+struct RenderOptions<'a> {
+    /// Where the footer sends readers to manage their subscriptions.
+    site_url: &'a str,
+    /// Opening line addressed to the recipient; omitted when `None`.
+    greeting: Option<&'a str>,
+    messages: &'a Messages,
+    today: NaiveDate,
+    /// Cap on the number of future shows listed; `None` lists them all.
+    future_limit: Option<usize>,
+    show_stalled: bool,
+    mode: DigestMode,
+    layout: Layout,
+    sort_order: SortOrder,
+    group_by: GroupBy,
+}
+
+/// How the digest HTML is laid out.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Layout {
+    /// Fixed-width lines inside a `<pre>` block.
+    #[default]
+    Pre,
+    /// A fluid, inline-styled layout that reflows on small screens.
+    Responsive,
+}
+
+impl Layout {
+    fn open(self) -> &'static str {
+        match self {
+            Layout::Pre => "<pre>",
+            Layout::Responsive => {
+                "<div style=\"font-family: -apple-system, Helvetica, Arial, sans-serif; \
+                 font-size: 15px; line-height: 1.4; max-width: 600px; margin: 0 auto; \
+                 padding: 8px\">"
+            }
+        }
+    }
+
+    fn close(self) -> &'static str {
+        match self {
+            Layout::Pre => "</pre>",
+            Layout::Responsive => "</div>",
+        }
+    }
+
+    /// Opens an emphasised run of rows; `<b>` can't wrap the responsive layout's block rows.
+    fn open_bold(self) -> &'static str {
+        match self {
+            Layout::Pre => "<b>",
+            Layout::Responsive => "<div style=\"font-weight: bold\">",
+        }
+    }
+
+    fn close_bold(self) -> &'static str {
+        match self {
+            Layout::Pre => "</b>",
+            Layout::Responsive => "</div>",
+        }
+    }
+
+    fn push_show(self, message: &mut String, show: &Show, messages: &Messages) {
+        match self {
+            Layout::Pre => message.push_str(&format!("{}<br />", show.html(messages))),
+            Layout::Responsive => message.push_str(&format!(
+                "<div style=\"padding: 6px 0; border-bottom: 1px solid #eeeeee\">{}</div>",
+                show.html(messages)
+            )),
+        }
+    }
+}
+
+/// Which way the digest runs in time. Shows are always fetched and truncated soonest-first.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum SortOrder {
+    #[default]
+    Asc,
+    /// Latest first, with today's shows at the bottom.
+    Desc,
+}
+
+/// Whether the daily digest's coming-days list gets a heading per day.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum GroupBy {
+    #[default]
+    None,
+    Day,
+}
+
+/// Which digest layout to send.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum DigestMode {
+    /// Today's shows, then the coming days'.
+    Daily,
+    /// One week-ahead list grouped by day.
+    Weekly,
+}
+
+impl FromStr for DigestMode {
+    type Err = String;
+
+    fn from_str(mode: &str) -> Result<Self, Self::Err> {
+        match mode {
+            "daily" => Ok(DigestMode::Daily),
+            "weekly" => Ok(DigestMode::Weekly),
+            other => Err(format!("unknown mode {other:?}, expected daily or weekly")),
+        }
+    }
+}
+
+/// What a run did, reported back to callers of the HTTP `/run` endpoint.
+#[derive(Serialize)]
+struct RunSummary {
+    shows: usize,
+    /// Shows whose lookup failed.
+    failed: usize,
+    mail_sent: bool,
+    /// Users whose digest could not be sent while others' went out.
+    send_failures: usize,
+}
+
+/// A failed pipeline run.
+struct RunFailure {
+    error: AppError,
+    /// Whether the failures behind `error` were already counted in the error metric.
+    counted: bool,
+}
+
+impl From<AppError> for RunFailure {
+    fn from(error: AppError) -> Self {
+        RunFailure {
+            error,
+            counted: false,
+        }
+    }
 }
 
 #[tokio::main]
 async fn main() {
     let mut config_file = "".to_owned();
     let mut no_mail = false;
+    let mut skip_aired = false;
+    let mut serve = false;
+    let mut limit = 0_usize;
+    let mut show_stalled = false;
+    let mut fail_on_empty = false;
+    let mut mode = "daily".to_owned();
+    let mut list = false;
+    let mut outbox = PathBuf::new();
+    let mut add_show = 0_i32;
+    let mut remove_show = 0_i32;
+    let mut print_config_template = false;
+    let mut skip_empty = false;
+    let mut prune = false;
+    let mut max_age = DEFAULT_PRUNE_MAX_AGE_DAYS;
+    let mut yes = false;
+    let mut send_test = "".to_owned();
+    let mut show_id = 0_i32;
     let _: Vec<String> = go_flag::parse(|flags| {
         flags.add_flag("config", &mut config_file);
         flags.add_flag("nomail", &mut no_mail);
+        flags.add_flag("skip-aired", &mut skip_aired);
+        flags.add_flag("serve", &mut serve);
+        flags.add_flag("limit", &mut limit);
+        flags.add_flag("show-stalled", &mut show_stalled);
+        flags.add_flag("fail-on-empty", &mut fail_on_empty);
+        flags.add_flag("mode", &mut mode);
+        flags.add_flag("list", &mut list);
+        flags.add_flag("outbox", &mut outbox);
+        flags.add_flag("add-show", &mut add_show);
+        flags.add_flag("remove-show", &mut remove_show);
+        flags.add_flag("print-config-template", &mut print_config_template);
+        flags.add_flag("skip-empty", &mut skip_empty);
+        flags.add_flag("prune", &mut prune);
+        flags.add_flag("max-age", &mut max_age);
+        flags.add_flag("yes", &mut yes);
+        flags.add_flag("send-test", &mut send_test);
+        flags.add_flag("show-id", &mut show_id);
     });
 
-    let config_content = fs::read_to_string(config_file).expect("config file not found");
-    let config = serde_json::from_str::<Config>(&config_content).expect("invalid config");
-    let show_ids = get_show_ids(&config).await.unwrap();
-    let shows = get_shows_parallel(show_ids)
-        .await
-        .expect("failed getting episode details");
-    if no_mail {
-        shows.iter().for_each(|show| println!("{show}"));
-        return ();
+    if print_config_template {
+        print!("{}", config_template::render());
+        return;
     }
-    let subscriptions = get_subscriptions(&config)
-        .await
-        .expect("failed to get subscriptions");
-    send_email(&shows, &config, subscriptions).expect("couldn't send the email");
-}
 
-fn send_email(
-    shows: &Vec<Show>,
-    config: &Config,
-    subscriptions: Vec<String>,
-) -> Result<(), Box<dyn Error>> {
-    let today = Local::now().date_naive();
-    let future_date_limit = today.checked_add_days(Days::new(FUTURE_DAY_LIMIT)).unwrap();
-    let today = Local::now().date_naive();
-    let mut today_shows = vec![];
-    let mut future_shows = vec![];
-    for show in shows {
-        if show.show_time.date_naive() > future_date_limit {
-            break;
+    let config_content = fs::read_to_string(&config_file).unwrap_or_else(|err| {
+        exit_with(
+            AppError::Config(err.to_string()),
+            &format!("cannot read {config_file}"),
+        )
+    });
+    let config_content = config_template::strip_comments(&config_content);
+    let mut config = serde_json::from_str::<Config>(&config_content)
+        .unwrap_or_else(|err| exit_with(AppError::Config(err.to_string()), "invalid config"));
+    if !outbox.as_os_str().is_empty() {
+        config.outbox_dir = Some(outbox);
+    }
+    config.skip_empty |= skip_empty;
+    let user_agent = config.user_agent.clone().unwrap_or_else(|| {
+        format!(
+            "tvnotifier/{} (+{})",
+            env!("CARGO_PKG_VERSION"),
+            config.site_url
+        )
+    });
+    let client = build_http_client(&user_agent, Duration::from_secs(config.http_timeout_secs))
+        .unwrap_or_else(|err| exit_with(AppError::Config(err.to_string()), "invalid user_agent"));
+    HTTP_CLIENT
+        .set(client)
+        .expect("HTTP client is only set up once");
+    let options = RunOptions {
+        skip_aired: skip_aired || config.skip_aired,
+        no_mail,
+        limit: (limit > 0).then_some(limit),
+        show_stalled: show_stalled || config.show_stalled,
+        fail_on_empty,
+        mode: mode
+            .parse()
+            .unwrap_or_else(|err| exit_with(AppError::Config(err), "invalid mode")),
+    };
+    if !send_test.is_empty() {
+        match send_test_email(&config, &send_test) {
+            Ok(response) => println!("test email to {send_test} accepted: {response}"),
+            Err(err) => exit_with(err, &format!("test email to {send_test} failed")),
         }
-        if show.show_time.date_naive() == today {
-            today_shows.push(show);
-        } else {
-            future_shows.push(show);
+        return;
+    }
+    if show_id != 0 {
+        if let Err(err) = debug_show(&config, options, show_id).await {
+            exit_with(err, &format!("failed to fetch show {show_id}"));
         }
+        return;
     }
-    let mut message = "<pre><b>Today's shows:<br />".to_owned();
-    if today_shows.len() > 0 {
-        for show in today_shows {
-            message.push_str(show.html().as_str());
-            message.push_str("<br />");
+    if serve {
+        if let Err(err) = server::serve(config, options).await {
+            exit_with(err, "failed to serve");
         }
-    } else {
-        message.push_str("</i>Nothing airing today.</i>");
+        return;
     }
-    message.push_str("</b><br /><br />");
-
-    if future_shows.len() > 0 {
-        message.push_str("Future shows:<br />");
-        for show in future_shows {
-            message.push_str(show.html().as_str());
-            message.push_str("<br />");
+    if list {
+        if let Err(err) = list_tracked_shows(&config, options).await {
+            exit_with(err, "failed to list tracked shows");
         }
+        return;
     }
-    message.push_str(format!("<br /><br />Manage subscriptions on <a href=\"{}\">TV Notifier UI</a>", config.site_url).as_ref());
-    message.push_str("</pre>");
-
-    let mut builder = Message::builder().from(config.from_email.parse().unwrap());
-
-    for sub in subscriptions {
-        builder = builder.to(sub.parse().unwrap());
+    if add_show != 0 {
+        if let Err(err) = add_tracked_show(&config, options, add_show).await {
+            exit_with(err, "failed to add show");
+        }
+        return;
     }
+    if remove_show != 0 {
+        if let Err(err) = remove_tracked_show(&config, remove_show).await {
+            exit_with(err, "failed to remove show");
+        }
+        return;
+    }
+    if prune {
+        if let Err(err) = prune_ended_shows(&config, options, max_age, yes).await {
+            exit_with(err, "failed to prune shows");
+        }
+        return;
+    }
+    let result = run(&config, options).await;
+    if let Some(pushgateway_url) = &config.pushgateway_url {
+        if let Err(err) = metrics::push(pushgateway_url).await {
+            eprintln!("failed to push metrics: {err}");
+        }
+    }
+    match result {
+        Err(err) => exit_with(err, "run failed"),
+        // Dry runs are used for monitoring, so make problems visible in the exit code.
+        Ok(summary) if no_mail => {
+            if summary.failed > 0 {
+                eprintln!("{} show(s) could not be fetched", summary.failed);
+                process::exit(EXIT_FETCH_FAILURES);
+            }
+            if options.fail_on_empty && summary.shows == 0 {
+                eprintln!("no shows were fetched");
+                process::exit(EXIT_EMPTY);
+            }
+        }
+        Ok(_) => {}
+    }
+}
 
-    let email = builder
-        .subject(format!("Upcoming shows for {}", today.format(DATE_FORMAT)))
-        .singlepart(SinglePart::html(message))
-        .unwrap();
-
-    let creds = Credentials::new(
-        config.smtp_user.to_string(),
-        config.smtp_password.to_string(),
-    );
-
-    // Open a remote connection to gmail
-    let mailer = SmtpTransport::relay(&config.smtp_host)
-        .unwrap()
-        .credentials(creds)
-        .build();
+/// Reports `err` and exits with the code for its category; see `AppError::exit_code`.
+fn exit_with(err: AppError, context: &str) -> ! {
+    eprintln!("{context}: {err}");
+    process::exit(err.exit_code());
+}
 
-    if let Err(e) = mailer.send(&email) {
-        return Err(Box::new(e));
+/// Prints the ID and TVMaze name of every tracked show.
+async fn list_tracked_shows(config: &Config, options: RunOptions) -> Result<(), AppError> {
+    let fetch_options = FetchOptions::new(config, options);
+    let show_ids = store::connect(config).await?.get_show_ids().await?;
+    let lookups: Vec<_> = show_ids
+        .into_iter()
+        .map(|show_id| (show_id, tokio::spawn(get_show_name(show_id, fetch_options))))
+        .collect();
+    println!("Shows:");
+    println!("{:<10}Name", "ID");
+    for (show_id, lookup) in lookups {
+        let name = lookup
+            .await
+            .unwrap_or_else(|err| Err(AppError::Http(format!("show lookup task failed: {err}"))))
+            .unwrap_or_else(|err| {
+                eprintln!("failed to look up show {show_id}: {err}");
+                "(unknown)".to_owned()
+            });
+        println!("{show_id:<10}{name}");
     }
     Ok(())
 }
 
-async fn get_show_ids(config: &Config) -> Result<Vec<i32>, Box<dyn Error>> {
-    let pg_connection_string = &config.pg_connection_string;
-    let builder = SslConnector::builder(SslMethod::tls())?;
-    let connector = MakeTlsConnector::new(builder.build());
-
-    let (client, connection) = tokio_postgres::connect(&pg_connection_string, connector).await?;
+/// Tracks `show_id` after checking that TVMaze knows about it.
+async fn add_tracked_show(
+    config: &Config,
+    options: RunOptions,
+    show_id: i32,
+) -> Result<(), AppError> {
+    let name = get_show_name(show_id, FetchOptions::new(config, options))
+        .await
+        .map_err(|err| err.context(&format!("cannot validate show {show_id}")))?;
+    if store::connect(config).await?.add_show(show_id).await? {
+        println!("Added show {show_id} ({name})");
+    } else {
+        println!("Show {show_id} ({name}) is already tracked");
+    }
+    Ok(())
+}
 
-    // The connection object performs the actual communication with the database,
-    // so spawn it off to run on its own.
-    tokio::spawn(async move { connection.await });
+/// Stops tracking `show_id`; removing a show that isn't tracked is not an error.
+async fn remove_tracked_show(config: &Config, show_id: i32) -> Result<(), AppError> {
+    if store::connect(config).await?.remove_show(show_id).await? {
+        println!("Removed show {show_id}");
+    } else {
+        println!("Show {show_id} was not tracked");
+    }
+    Ok(())
+}
 
-    let ids: Vec<i32> = client
-        .query("SELECT id FROM shows", &[])
-        .await?
+/// Lists tracked shows that have ended and last aired more than `max_age_days` ago, removing
+/// them only when `confirmed`.
+///
+/// Shows that can't be looked up are kept.
+async fn prune_ended_shows(
+    config: &Config,
+    options: RunOptions,
+    max_age_days: u64,
+    confirmed: bool,
+) -> Result<(), AppError> {
+    let fetch_options = FetchOptions::new(config, options);
+    let store = store::connect(config).await?;
+    let show_ids = dedupe_show_ids(store.get_show_ids().await?);
+    let lookups: Vec<_> = show_ids
         .into_iter()
-        .map(|row| row.get(0))
+        .map(|show_id| {
+            let url = format!("https://api.tvmaze.com/shows/{show_id}?embed[]=previousepisode");
+            let lookup =
+                tokio::spawn(async move { get_json_with_retries(&url, fetch_options).await });
+            (show_id, lookup)
+        })
         .collect();
-    Ok(ids)
+    let mut stale = vec![];
+    for (show_id, lookup) in lookups {
+        match lookup.await {
+            Ok(Ok(show)) => {
+                if let Some(reason) = stale_reason(&show, fetch_options.now, max_age_days) {
+                    let name = show["name"].as_str().unwrap_or("unknown").to_owned();
+                    stale.push((show_id, name, reason));
+                }
+            }
+            Ok(Err(err)) => eprintln!("keeping show {show_id}, lookup failed: {err}"),
+            Err(err) => eprintln!("keeping show {show_id}, lookup task failed: {err}"),
+        }
+    }
+    if stale.is_empty() {
+        println!("Nothing to prune");
+        return Ok(());
+    }
+    for (show_id, name, reason) in &stale {
+        if confirmed {
+            store.remove_show(*show_id).await?;
+            println!("Removed show {show_id} ({name}): {reason}");
+        } else {
+            println!("Would remove show {show_id} ({name}): {reason}");
+        }
+    }
+    if !confirmed {
+        println!("Run again with --yes to remove these shows");
+    }
+    Ok(())
 }
 
-async fn get_subscriptions(config: &Config) -> Result<Vec<String>, Box<dyn Error>> {
-    let pg_connection_string = &config.pg_connection_string;
-    let builder = SslConnector::builder(SslMethod::tls())?;
-    let connector = MakeTlsConnector::new(builder.build());
+/// Why `show` is safe to stop tracking, or `None` if it isn't.
+fn stale_reason(show: &Value, now: DateTime<Local>, max_age_days: u64) -> Option<String> {
+    if show["status"].as_str() != Some("Ended") {
+        return None;
+    }
+    let last_aired = show["_embedded"]["previousepisode"]["airstamp"]
+        .as_str()
+        .and_then(|airstamp| DateTime::parse_from_rfc3339(airstamp).ok());
+    match last_aired {
+        Some(last_aired) => {
+            let cutoff = now.checked_sub_days(Days::new(max_age_days))?;
+            (last_aired < cutoff).then(|| {
+                format!(
+                    "ended, last aired {}",
+                    last_aired.with_timezone(&Local).format("%Y-%m-%d")
+                )
+            })
+        }
+        None => Some("ended, never aired".to_owned()),
+    }
+}
 
-    let (client, connection) = tokio_postgres::connect(&pg_connection_string, connector).await?;
+/// Fetches the tracked shows and, unless `no_mail` is set, emails the digest to every subscriber.
+///
+/// A run that fails transiently, e.g. on a timeout, is started over up to `run_retries` times.
+/// Only the final outcome counts towards the error metric.
+/// The final outcome is reported to `completion_webhook_url` if one is configured.
+async fn run(config: &Config, options: RunOptions) -> Result<RunSummary, AppError> {
+    let started = Instant::now();
+    let result = run_with_retries(config, options).await.map_err(|failure| {
+        if !failure.counted {
+            metrics::increment(metrics::ERRORS, &[("category", failure.error.category())]);
+        }
+        failure.error
+    });
+    if let Some(webhook_url) = &config.completion_webhook_url {
+        if let Err(err) =
+            notify_completion(http_client(), webhook_url, &result, started.elapsed()).await
+        {
+            eprintln!("failed to call the completion webhook: {err}");
+        }
+    }
+    result
+}
 
-    // The connection object performs the actual communication with the database,
-    // so spawn it off to run on its own.
-    tokio::spawn(async move { connection.await });
+async fn run_with_retries(config: &Config, options: RunOptions) -> Result<RunSummary, RunFailure> {
+    let mut attempt = 0;
+    loop {
+        let result = run_pipeline(config, options).await;
+        if let Err(RunFailure { error: err, .. }) = &result {
+            if err.is_transient() && attempt < config.run_retries {
+                attempt += 1;
+                eprintln!(
+                    "run failed ({err}), retrying in {}s ({attempt}/{})",
+                    config.run_retry_delay_secs, config.run_retries
+                );
+                tokio::time::sleep(Duration::from_secs(config.run_retry_delay_secs)).await;
+                continue;
+            }
+        }
+        return result;
+    }
+}
 
-    let subscriptions: Vec<String> = client
-        .query("select email from users where email is not null", &[])
+/// POSTs the outcome of a run to `webhook_url` as JSON.
+///
+/// The client's timeouts bound the call, so a stalled endpoint can't hold up the run once the
+/// digest has gone out.
+async fn notify_completion(
+    client: &reqwest::Client,
+    webhook_url: &str,
+    result: &Result<RunSummary, AppError>,
+    elapsed: Duration,
+) -> Result<(), reqwest::Error> {
+    let body = match result {
+        Ok(summary) => serde_json::json!({
+            "status": "ok",
+            "shows": summary.shows,
+            "failed": summary.failed,
+            "mail_sent": summary.mail_sent,
+            "send_failures": summary.send_failures,
+            "duration_secs": elapsed.as_secs_f64(),
+        }),
+        Err(err) => serde_json::json!({
+            "status": "error",
+            "category": err.category(),
+            "error": err.to_string(),
+            "duration_secs": elapsed.as_secs_f64(),
+        }),
+    };
+    client
+        .post(webhook_url)
+        .header(CONTENT_TYPE, "application/json")
+        .body(body.to_string())
+        .send()
         .await?
+        .error_for_status()?;
+    Ok(())
+}
+
+async fn run_pipeline(config: &Config, options: RunOptions) -> Result<RunSummary, RunFailure> {
+    let fetch_options = FetchOptions::new(config, options);
+    let store = store::connect(config)
+        .await
+        .map_err(|err| err.context("failed to connect to the database"))?;
+    let show_ids = store
+        .get_show_ids()
+        .await
+        .map_err(|err| err.context("failed to get show ids"))?;
+    let show_ids = dedupe_show_ids(show_ids);
+    let mut digest = match config.fetch_strategy {
+        FetchStrategy::PerShow => get_shows_parallel(show_ids, fetch_options).await,
+        FetchStrategy::Schedule => {
+            if config.lookback_days > 0 || options.show_stalled {
+                eprintln!(
+                    "lookback_days and show_stalled don't apply to the schedule strategy, ignoring them"
+                );
+            }
+            match get_shows_from_schedule(&show_ids, &config.schedule_country, fetch_options).await
+            {
+                Ok(digest) => digest,
+                Err(err) => {
+                    eprintln!("schedule fetch failed, falling back to per-show lookups: {err}");
+                    get_shows_parallel(show_ids, fetch_options).await
+                }
+            }
+        }
+    };
+    sort_by_priority(&mut digest.shows, &config.show_priorities);
+    if config.highlight_finales {
+        mark_finales(&mut digest, fetch_options).await;
+    }
+    if config.highlight_changes {
+        let previous = store
+            .get_last_digest()
+            .await
+            .map_err(|err| err.context("failed to load the previous digest"))?;
+        if config.highlight_reschedules {
+            mark_reschedules(&mut digest, &previous);
+        }
+        compare_with_previous(&mut digest, previous, fetch_options.now);
+    }
+    metrics::add(metrics::SHOWS_FETCHED, &[], digest.shows.len() as u64);
+    for failure in &digest.failures {
+        metrics::increment(metrics::ERRORS, &[("category", failure.error.category())]);
+        eprintln!(
+            "failed getting episode details for show {}: {}",
+            failure.show_id, failure.error
+        );
+    }
+    if options.no_mail {
+        digest.shows.iter().for_each(|show| println!("{show}"));
+        digest
+            .recently_aired
+            .iter()
+            .for_each(|show| println!("Recently aired: {show}"));
+        digest.removed.iter().for_each(|entry| {
+            println!(
+                "No longer scheduled: {} ({})",
+                entry.show_name, entry.episode_name
+            )
+        });
+        if options.show_stalled {
+            digest
+                .stalled
+                .iter()
+                .for_each(|show| println!("No upcoming episodes: {}", show.name));
+        }
+        return Ok(RunSummary {
+            shows: digest.shows.len(),
+            failed: digest.failures.len(),
+            mail_sent: false,
+            send_failures: 0,
+        });
+    }
+    if config.abort_on_fetch_failure {
+        if let Some(failure) = digest.failures.pop() {
+            return Err(RunFailure {
+                error: failure.error.context(&format!(
+                    "failed getting episode details for show {}",
+                    failure.show_id
+                )),
+                counted: true,
+            });
+        }
+    } else if !digest.failures.is_empty() {
+        eprintln!(
+            "sending a partial digest without {} show(s)",
+            digest.failures.len()
+        );
+    }
+    if let Some(quiet_hours) = &config.quiet_hours {
+        if quiet_hours.contains(fetch_options.now.time()) {
+            eprintln!(
+                "within quiet hours ({} to {}), skipping send",
+                quiet_hours.start.format("%H:%M"),
+                quiet_hours.end.format("%H:%M")
+            );
+            return Ok(RunSummary {
+                shows: digest.shows.len(),
+                failed: digest.failures.len(),
+                mail_sent: false,
+                send_failures: 0,
+            });
+        }
+    }
+    let channels = store
+        .get_notification_channels()
+        .await
+        .map_err(|err| err.context("failed to get notification channels"))?;
+    let recipients = email_recipients(channels);
+    let send_config = config.clone();
+    // SMTP delivery blocks, so keep it off the async workers; in --serve mode they also answer
+    // requests.
+    let (digest, send_result) = tokio::task::spawn_blocking(move || {
+        let render_options = RenderOptions {
+            site_url: &send_config.site_url,
+            greeting: None,
+            messages: send_config.locale.messages(),
+            today: fetch_options.now.date_naive(),
+            future_limit: options.limit,
+            show_stalled: options.show_stalled,
+            mode: options.mode,
+            layout: send_config.layout,
+            sort_order: send_config.sort_order,
+            group_by: send_config.group_by,
+        };
+        let result = send_email(&digest, &send_config, &recipients, &render_options);
+        (digest, result)
+    })
+    .await
+    .map_err(|err| AppError::Smtp(format!("send task failed: {err}")))?;
+    let mut send_summary = send_result.map_err(|err| err.context("couldn't send the email"))?;
+    // A partial send is reported in the summary, but one that reached nobody fails the run.
+    if send_summary.sent == 0 {
+        if let Some((user_id, err)) = send_summary.failed.pop() {
+            // send_email already counted each user's failure.
+            return Err(RunFailure {
+                error: err.context(&format!("couldn't send the email to user {user_id}")),
+                counted: true,
+            });
+        }
+    }
+    let sent = send_summary.sent;
+    if config.highlight_changes && sent > 0 {
+        let entries: Vec<DigestEntry> = digest
+            .shows
+            .iter()
+            .map(|show| DigestEntry {
+                show_id: show.id,
+                show_name: show.name.clone(),
+                episode_name: show.episode_name.clone(),
+                show_time: show.show_time,
+            })
+            .collect();
+        if let Err(err) = store.save_digest(&entries).await {
+            eprintln!("failed to save digest history: {err}");
+        }
+    }
+    Ok(RunSummary {
+        shows: digest.shows.len(),
+        failed: digest.failures.len(),
+        mail_sent: sent > 0,
+        send_failures: send_summary.failed.len(),
+    })
+}
+
+/// Marks shows that weren't in `previous` as new and collects the previous digest's upcoming
+/// episodes that have since dropped off.
+///
+/// Episodes are matched by show and episode name. Nothing is marked when there is no previous
+/// digest yet, so the first run doesn't flag every show.
+fn compare_with_previous(digest: &mut Digest, previous: Vec<DigestEntry>, now: DateTime<Local>) {
+    if previous.is_empty() {
+        return;
+    }
+    let previous_keys: HashSet<(i32, &str)> = previous
+        .iter()
+        .map(|entry| (entry.show_id, entry.episode_name.as_str()))
+        .collect();
+    for show in &mut digest.shows {
+        show.new = !previous_keys.contains(&(show.id, show.episode_name.as_str()));
+    }
+    let current_keys: HashSet<(i32, &str)> = digest
+        .shows
+        .iter()
+        .map(|show| (show.id, show.episode_name.as_str()))
+        .collect();
+    digest.removed = previous
         .into_iter()
-        .map(|row| row.get(0))
+        .filter(|entry| entry.show_time > now)
+        .filter(|entry| !current_keys.contains(&(entry.show_id, entry.episode_name.as_str())))
         .collect();
-    Ok(subscriptions)
 }
 
-fn parse_show(show_id: i32, show_name: &str, episode_details: &Map<String, Value>) -> Show {
-    let episode_name = episode_details["name"].as_str().unwrap_or_default();
-    let airstamp = episode_details["airstamp"].as_str().unwrap_or_default();
-    let show_time = DateTime::parse_from_rfc3339(airstamp).unwrap_or_default();
-    Show {
-        id: show_id,
-        name: show_name.to_owned(),
-        episode_name: episode_name.to_owned(),
-        show_time: show_time.with_timezone(&chrono::Local),
+/// Records the previous air time of every episode the previous digest listed at another time.
+fn mark_reschedules(digest: &mut Digest, previous: &[DigestEntry]) {
+    let previous_times: HashMap<(i32, &str), DateTime<Local>> = previous
+        .iter()
+        .map(|entry| {
+            (
+                (entry.show_id, entry.episode_name.as_str()),
+                entry.show_time,
+            )
+        })
+        .collect();
+    for show in &mut digest.shows {
+        show.rescheduled_from = previous_times
+            .get(&(show.id, show.episode_name.as_str()))
+            .copied()
+            .filter(|previous_time| *previous_time != show.show_time);
     }
 }
 
-async fn get_next_episode(show_id: i32) -> Result<Option<Show>, Box<dyn Error>> {
-    let url = format!(
-        "https://api.tvmaze.com/shows/{show_id}?embed[]=nextepisode&embed[]=previousepisode"
-    );
-    let response = reqwest::get(url).await?;
-    if let Err(err) = response.error_for_status_ref() {
-        return Err(Box::new(err));
+/// One user's share of the send: who they are and where their digest goes.
+#[derive(Default)]
+struct Recipient {
+    name: Option<String>,
+    addresses: Vec<String>,
+}
+
+/// Groups the enabled email channels by user, so each user gets a single message.
+fn email_recipients(channels: Vec<NotificationChannel>) -> BTreeMap<String, Recipient> {
+    let mut recipients: BTreeMap<String, Recipient> = BTreeMap::new();
+    for channel in channels {
+        match channel.channel_type.as_str() {
+            "email" => {
+                let recipient = recipients.entry(channel.user_id).or_default();
+                recipient.name = recipient.name.take().or(channel.user_name);
+                recipient.addresses.push(channel.destination);
+            }
+            other => eprintln!(
+                "skipping unsupported {other} channel for user {}",
+                channel.user_id
+            ),
+        }
     }
-    let body = response.text().await?;
-    let show: Value = serde_json::from_str(&body)?;
-    let show_name = show["name"].as_str().ok_or("show name not found")?;
-    let embedded = &show["_embedded"];
-    if !embedded.is_object() {
-        return Ok(None);
+    recipients
+}
+
+/// Orders shows airing at the same time by descending priority; unlisted shows have priority 0.
+fn sort_by_priority(shows: &mut [Show], priorities: &HashMap<i32, i32>) {
+    shows.sort_by_key(|show| {
+        let priority = priorities.get(&show.id).copied().unwrap_or_default();
+        (show.show_time, Reverse(priority))
+    });
+}
+
+/// Renders the digest body in the layout selected by `options.mode`.
+fn build_email_html(digest: &Digest, options: &RenderOptions) -> String {
+    let messages = options.messages;
+    let shows = listed_shows(digest, options.today);
+    let mut message = options.layout.open().to_owned();
+    if let Some(greeting) = options.greeting {
+        message.push_str(&format!("{greeting}<br /><br />"));
     }
-    let prev_episode = &embedded["previousepisode"];
-    if prev_episode.is_object() {
-        let prev_show = parse_show(
-            show_id,
-            show_name,
-            prev_episode
-                .as_object()
-                .expect("previous epsiode not an object"),
-        );
-        if prev_show.show_time.date_naive() == Local::now().date_naive() {
-            return Ok(Some(prev_show));
+    match options.mode {
+        DigestMode::Daily => push_daily_sections(&mut message, &shows, options),
+        DigestMode::Weekly => push_weekly_sections(&mut message, &shows, options),
+    }
+
+    if !digest.recently_aired.is_empty() {
+        message.push_str(&format!("<br />{}<br />", messages.recently_aired_heading));
+        for show in &digest.recently_aired {
+            options.layout.push_show(&mut message, show, messages);
+        }
+    }
+    if !digest.removed.is_empty() {
+        message.push_str(&format!("<br />{}<br />", messages.removed_heading));
+        for entry in &digest.removed {
+            message.push_str(&format!(
+                "<a href=\"https://www.tvmaze.com/shows/{}\">{}</a> ({})<br />",
+                entry.show_id,
+                escape_html(&entry.show_name),
+                escape_html(&entry.episode_name)
+            ));
         }
     }
-    let next_episode = &embedded["nextepisode"];
-    if !next_episode.is_object() {
-        return Ok(None);
+    if options.show_stalled && !digest.stalled.is_empty() {
+        message.push_str(&format!("<br />{}<br />", messages.stalled_heading));
+        for show in &digest.stalled {
+            message.push_str(&format!(
+                "<a href=\"https://www.tvmaze.com/shows/{}\">{}</a><br />",
+                show.id,
+                escape_html(&show.name)
+            ));
+        }
     }
-    let next_show = parse_show(
-        show_id,
-        show_name,
-        next_episode
-            .as_object()
-            .expect("next epsiode not an object"),
+    message.push_str(
+        format!(
+            "<br /><br />{} <a href=\"{}\">TV Notifier UI</a>",
+            messages.manage_subscriptions,
+            escape_html(options.site_url)
+        )
+        .as_ref(),
     );
-    Ok(Some(next_show))
+    message.push_str(options.layout.close());
+    message
 }
 
-async fn get_shows_parallel(show_ids: Vec<i32>) -> Result<Vec<Show>, Box<dyn Error>> {
-    let mut show_handles = vec![];
-    for show_id in show_ids {
-        show_handles.push(tokio::spawn(async move {
-            let next_episode = get_next_episode(show_id).await;
-            match next_episode {
-                Ok(show) => Ok(show),
-                Err(err) => Err(err.to_string()),
+/// Escapes text for use in HTML content and double-quoted attributes.
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Percent-encodes `text` for use as a single URL path segment.
+fn encode_path_segment(text: &str) -> String {
+    let mut encoded = String::with_capacity(text.len());
+    for byte in text.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                encoded.push(byte as char)
             }
-        }))
+            _ => encoded.push_str(&format!("%{byte:02X}")),
+        }
+    }
+    encoded
+}
+
+/// The shows airing from `today` through the future day limit, in digest order.
+fn listed_shows(digest: &Digest, today: NaiveDate) -> Vec<&Show> {
+    let future_date_limit = today.checked_add_days(Days::new(FUTURE_DAY_LIMIT)).unwrap();
+    digest
+        .shows
+        .iter()
+        // Shows without a usable air time parse as 1970 and must not pass for today's.
+        .filter(|show| (today..=future_date_limit).contains(&show.show_time.date_naive()))
+        .collect()
+}
+
+/// Splits shows into today's and the coming days'.
+///
+/// Today's shows are always listed in full; the future list is cut to `future_limit` entries,
+/// keeping the soonest since `shows` is sorted by air time. When grouped by day, whole days are
+/// dropped as in the weekly digest.
+fn push_daily_sections(message: &mut String, shows: &[&Show], options: &RenderOptions) {
+    let (mut today_shows, mut future_shows): (Vec<&Show>, Vec<&Show>) = shows
+        .iter()
+        .partition(|show| show.show_time.date_naive() == options.today);
+    let messages = options.messages;
+    let mut today_section = format!(
+        "{}{}<br />",
+        options.layout.open_bold(),
+        messages.today_heading
+    );
+    if options.sort_order == SortOrder::Desc {
+        today_shows.reverse();
+    }
+    if !today_shows.is_empty() {
+        for show in today_shows {
+            options.layout.push_show(&mut today_section, show, messages);
+        }
+    } else {
+        today_section.push_str(&format!("<i>{}</i>", messages.nothing_today));
+    }
+    today_section.push_str(options.layout.close_bold());
+    today_section.push_str("<br /><br />");
+
+    let mut future_section = String::new();
+    if !future_shows.is_empty() {
+        future_section.push_str(&format!("{}<br />", messages.future_heading));
+        let omitted = match options.group_by {
+            GroupBy::None => {
+                let total = future_shows.len();
+                if let Some(limit) = options.future_limit {
+                    future_shows.truncate(limit);
+                }
+                if options.sort_order == SortOrder::Desc {
+                    future_shows.reverse();
+                }
+                for show in &future_shows {
+                    options
+                        .layout
+                        .push_show(&mut future_section, show, messages);
+                }
+                total - future_shows.len()
+            }
+            GroupBy::Day => {
+                let mut days = group_by_day(&future_shows);
+                let omitted = truncate_days(&mut days, options.future_limit);
+                push_days(&mut future_section, days, options);
+                omitted
+            }
+        };
+        push_truncation_note(&mut future_section, omitted, messages);
+    }
+
+    match options.sort_order {
+        SortOrder::Asc => {
+            message.push_str(&today_section);
+            message.push_str(&future_section);
+        }
+        SortOrder::Desc => {
+            if !future_section.is_empty() {
+                future_section.push_str("<br />");
+            }
+            message.push_str(&future_section);
+            message.push_str(&today_section);
+        }
+    }
+}
+
+/// Lists the whole week ahead under one heading per day.
+///
+/// `future_limit` caps the total number of shows, but only whole days are dropped unless the
+/// first day alone is over the limit.
+fn push_weekly_sections(message: &mut String, shows: &[&Show], options: &RenderOptions) {
+    let mut days = group_by_day(shows);
+    let omitted = truncate_days(&mut days, options.future_limit);
+
+    let messages = options.messages;
+    message.push_str(&format!("<b>{}</b><br />", messages.week_heading));
+    if days.is_empty() {
+        message.push_str(&format!("<i>{}</i><br />", messages.nothing_this_week));
+    }
+    push_days(message, days, options);
+    push_truncation_note(message, omitted, messages);
+}
+
+/// Splits shows sorted by air time into runs airing on the same day.
+fn group_by_day<'a>(shows: &[&'a Show]) -> Vec<Vec<&'a Show>> {
+    let mut days: Vec<Vec<&Show>> = vec![];
+    for show in shows {
+        match days.last_mut() {
+            Some(day) if day[0].show_time.date_naive() == show.show_time.date_naive() => {
+                day.push(show)
+            }
+            _ => days.push(vec![show]),
+        }
+    }
+    days
+}
+
+/// Cuts `days` to at most `limit` shows in total, returning how many were dropped.
+///
+/// Only whole days are dropped, unless the first day alone is over the limit.
+fn truncate_days(days: &mut Vec<Vec<&Show>>, limit: Option<usize>) -> usize {
+    let Some(limit) = limit else {
+        return 0;
+    };
+    let mut omitted = 0;
+    let mut listed = 0;
+    let mut keep_days = 0;
+    for day in days.iter() {
+        if listed + day.len() > limit {
+            break;
+        }
+        listed += day.len();
+        keep_days += 1;
+    }
+    if keep_days == 0 && !days.is_empty() {
+        keep_days = 1;
+        omitted += days[0].len().saturating_sub(limit);
+        days[0].truncate(limit);
+    }
+    omitted += days[keep_days..].iter().map(Vec::len).sum::<usize>();
+    days.truncate(keep_days);
+    omitted
+}
+
+/// Lists each day's shows under a date heading, in `options.sort_order`.
+fn push_days(message: &mut String, mut days: Vec<Vec<&Show>>, options: &RenderOptions) {
+    if options.sort_order == SortOrder::Desc {
+        days.reverse();
+        days.iter_mut().for_each(|day| day.reverse());
+    }
+    let messages = options.messages;
+    for day in days {
+        message.push_str(&format!(
+            "<br /><b>{}</b><br />",
+            day[0]
+                .show_time
+                .format_localized(messages.date_format, messages.chrono_locale)
+        ));
+        for show in day {
+            options.layout.push_show(message, show, messages);
+        }
+    }
+}
+
+fn push_truncation_note(message: &mut String, omitted: usize, messages: &Messages) {
+    if omitted > 0 {
+        let note = messages.more.replace("{}", &omitted.to_string());
+        message.push_str(&format!("<i>{note}</i><br />"));
+    }
+}
+
+/// Sends the digest to each user's email addresses, a few users at a time.
+///
+/// A user whose message fails is recorded in the summary and the rest are still sent.
+fn send_email(
+    digest: &Digest,
+    config: &Config,
+    recipients: &BTreeMap<String, Recipient>,
+    render_options: &RenderOptions,
+) -> Result<SendSummary, AppError> {
+    if recipients.is_empty() {
+        eprintln!("no subscribers, skipping send");
+        return Ok(SendSummary::default());
+    }
+    // Every user gets the same episodes, so an empty digest is empty for all of them.
+    if config.skip_empty
+        && listed_shows(digest, render_options.today).is_empty()
+        && digest.recently_aired.is_empty()
+    {
+        eprintln!("nothing airing, skipping send");
+        return Ok(SendSummary::default());
+    }
+    let mailer = build_transport(config)?;
+
+    let messages = render_options.messages;
+    let today = render_options
+        .today
+        .format_localized(messages.date_format, messages.chrono_locale)
+        .to_string();
+    let subject = match render_options.mode {
+        DigestMode::Daily => messages.daily_subject.replace("{}", &today),
+        DigestMode::Weekly => messages.weekly_subject.replace("{}", &today),
+    };
+    let from = Mailbox::new(config.from_name.clone(), config.from_email.parse()?);
+    let reply_to: Option<Mailbox> = match &config.reply_to {
+        Some(reply_to) => Some(reply_to.parse()?),
+        None => None,
+    };
+    let show_count = listed_shows(digest, render_options.today).len();
+    let build_message = |user_id: &str, recipient: &Recipient| -> Result<Message, AppError> {
+        let greeting = match &recipient.name {
+            Some(name) => messages.named_greeting.replace("{}", &escape_html(name)),
+            None => messages.greeting.to_owned(),
+        };
+        let manage_url = match &config.manage_url_template {
+            Some(template) => template.replace("{user_id}", &encode_path_segment(user_id)),
+            None => config.site_url.clone(),
+        };
+        let message = build_email_html(
+            digest,
+            &RenderOptions {
+                site_url: &manage_url,
+                greeting: Some(&greeting),
+                ..*render_options
+            },
+        );
+        let mut builder = Message::builder().from(from.clone());
+        if let Some(reply_to) = &reply_to {
+            builder = builder.reply_to(reply_to.clone());
+        }
+        for address in &recipient.addresses {
+            builder = builder.to(address.parse()?);
+        }
+        Ok(builder
+            .subject(subject.clone())
+            .singlepart(SinglePart::html(message))?)
+    };
+    let send_to = |user_id: &str, recipient: &Recipient| -> Result<(), AppError> {
+        // Audited whether the message failed to build or to deliver.
+        let result = build_message(user_id, recipient)
+            .and_then(|email| mailer.send(&email))
+            .map(|_| ());
+        if let Some(audit_log_path) = &config.audit_log_path {
+            let entry = serde_json::json!({
+                "timestamp": Local::now().to_rfc3339(),
+                "user_id": user_id,
+                "recipients": recipient.addresses,
+                "subject": subject,
+                "shows": show_count,
+                "status": if result.is_ok() { "sent" } else { "failed" },
+                "error": result.as_ref().err().map(ToString::to_string),
+            });
+            if let Err(err) = append_audit_entry(audit_log_path, &entry) {
+                eprintln!(
+                    "failed to write audit log {}: {err}",
+                    audit_log_path.display()
+                );
+            }
+        }
+        result
+    };
+
+    // Workers take users off a shared queue so at most `send_concurrency` messages are in
+    // flight; the SMTP transport pools its connections across them.
+    let queue = Mutex::new(recipients.iter());
+    let results = Mutex::new(vec![]);
+    std::thread::scope(|scope| {
+        for _ in 0..config.send_concurrency.clamp(1, recipients.len()) {
+            scope.spawn(|| loop {
+                let Some((user_id, recipient)) = queue.lock().unwrap().next() else {
+                    break;
+                };
+                let result = send_to(user_id, recipient);
+                results.lock().unwrap().push((user_id.clone(), result));
+            });
+        }
+    });
+
+    let mut summary = SendSummary::default();
+    for (user_id, result) in results.into_inner().unwrap() {
+        match result {
+            Ok(()) => {
+                metrics::increment(metrics::EMAILS_SENT, &[]);
+                summary.sent += 1;
+            }
+            Err(err) => {
+                metrics::increment(metrics::ERRORS, &[("category", err.category())]);
+                eprintln!("failed to send to user {user_id}: {err}");
+                summary.failed.push((user_id, err));
+            }
+        }
+    }
+    summary.failed.sort_by(|a, b| a.0.cmp(&b.0));
+    Ok(summary)
+}
+
+/// How the per-user sends of one digest went.
+#[derive(Default, Debug)]
+struct SendSummary {
+    sent: usize,
+    /// Users whose message could not be built or delivered, by user ID.
+    failed: Vec<(String, AppError)>,
+}
+
+/// Appends `entry` to the audit log as one JSON line.
+///
+/// Each line is written with a single append so lines from overlapping runs don't interleave.
+fn append_audit_entry(path: &Path, entry: &Value) -> std::io::Result<()> {
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    file.write_all(format!("{entry}\n").as_bytes())
+}
+
+/// Sends a minimal message to `address` through the configured transport, skipping the fetch,
+/// and returns the relay's response.
+fn send_test_email(config: &Config, address: &str) -> Result<String, AppError> {
+    let mailer = build_transport(config)?;
+    let email = Message::builder()
+        .from(Mailbox::new(
+            config.from_name.clone(),
+            config.from_email.parse()?,
+        ))
+        .to(address.parse()?)
+        .subject("TV Notifier test email")
+        .singlepart(SinglePart::plain(
+            "This is a test message from TV Notifier. If you can read it, email delivery works."
+                .to_owned(),
+        ))?;
+    mailer.send(&email)
+}
+
+/// Where digest messages are delivered.
+enum Mailer {
+    /// Relays by host, in the order they are tried.
+    Smtp(Vec<(String, SmtpTransport)>),
+    File(FileTransport),
+}
+
+impl Mailer {
+    /// Delivers `email`, returning a description of where it went and what the relay said.
+    fn send(&self, email: &Message) -> Result<String, AppError> {
+        match self {
+            Mailer::Smtp(relays) => {
+                let mut last_error = None;
+                for (host, transport) in relays {
+                    let started = Instant::now();
+                    let result = transport.send(email);
+                    metrics::record_call("smtp", started.elapsed());
+                    match result {
+                        Ok(response) => {
+                            let message = response.message().collect::<Vec<_>>().join(" ");
+                            return Ok(format!("relay {host}: {} {message}", response.code()));
+                        }
+                        Err(err) => {
+                            eprintln!("relay {host} failed: {err}");
+                            last_error =
+                                Some(AppError::from(err).context(&format!("relay {host}")));
+                        }
+                    }
+                }
+                Err(last_error
+                    .unwrap_or_else(|| AppError::Config("no SMTP relays configured".to_owned())))
+            }
+            Mailer::File(transport) => {
+                let id = transport.send(email)?;
+                Ok(format!("outbox message {id}"))
+            }
+        }
+    }
+}
+
+fn build_transport(config: &Config) -> Result<Mailer, AppError> {
+    if let Some(outbox_dir) = &config.outbox_dir {
+        fs::create_dir_all(outbox_dir).map_err(|err| {
+            AppError::Config(format!(
+                "cannot create outbox {}: {err}",
+                outbox_dir.display()
+            ))
+        })?;
+        return Ok(Mailer::File(FileTransport::new(outbox_dir)));
+    }
+
+    if config.smtp_relays.is_empty() {
+        let creds = Credentials::new(
+            config.smtp_user.to_string(),
+            config.smtp_password.to_string(),
+        );
+
+        // Open a remote connection to gmail
+        let transport = SmtpTransport::relay(&config.smtp_host)?
+            .credentials(creds)
+            .build();
+        return Ok(Mailer::Smtp(vec![(config.smtp_host.clone(), transport)]));
+    }
+    let mut relays = vec![];
+    for relay in &config.smtp_relays {
+        let mut builder = match relay.encryption {
+            SmtpEncryption::Tls => SmtpTransport::relay(&relay.host)?,
+            SmtpEncryption::Starttls => SmtpTransport::starttls_relay(&relay.host)?,
+            SmtpEncryption::None => SmtpTransport::builder_dangerous(&relay.host),
+        };
+        if let Some(port) = relay.port {
+            builder = builder.port(port);
+        }
+        if !relay.user.is_empty() {
+            builder =
+                builder.credentials(Credentials::new(relay.user.clone(), relay.password.clone()));
+        }
+        relays.push((relay.host.clone(), builder.build()));
+    }
+    Ok(Mailer::Smtp(relays))
+}
+
+fn parse_show(
+    show_id: i32,
+    show_name: &str,
+    image: Option<&str>,
+    episode_details: &Map<String, Value>,
+) -> Show {
+    let episode_name = episode_details
+        .get("name")
+        .and_then(Value::as_str)
+        .unwrap_or_default();
+    let airstamp = episode_details
+        .get("airstamp")
+        .and_then(Value::as_str)
+        .unwrap_or_default();
+    let show_time = DateTime::parse_from_rfc3339(airstamp).unwrap_or_default();
+    Show {
+        id: show_id,
+        name: show_name.to_owned(),
+        episode_name: episode_name.to_owned(),
+        show_time: show_time.with_timezone(&chrono::Local),
+        image: image.map(str::to_owned),
+        runtime: int_field(episode_details, "runtime"),
+        new: false,
+        season: int_field(episode_details, "season"),
+        number: int_field(episode_details, "number"),
+        finale: false,
+        rescheduled_from: None,
+    }
+}
+
+/// Reads an optional integer field, treating a missing, null or out-of-range value as unknown.
+fn int_field(details: &Map<String, Value>, key: &str) -> Option<i32> {
+    details
+        .get(key)
+        .and_then(Value::as_i64)
+        .and_then(|value| i32::try_from(value).ok())
+}
+
+/// Full-jitter exponential backoff: a random delay between zero and `base * 2^attempt`.
+fn backoff_delay(attempt: u32, base: Duration, rng: &mut impl Rng) -> Duration {
+    let base_ms = u64::try_from(base.as_millis()).unwrap_or(u64::MAX);
+    let cap_ms = base_ms.saturating_mul(1 << attempt.min(16));
+    Duration::from_millis(rng.random_range(0..=cap_ms))
+}
+
+/// The client every outbound HTTP request goes through, set up by `main` with the configured
+/// User-Agent.
+static HTTP_CLIENT: OnceLock<reqwest::Client> = OnceLock::new();
+
+fn http_client() -> &'static reqwest::Client {
+    HTTP_CLIENT.get_or_init(|| {
+        build_http_client(
+            concat!("tvnotifier/", env!("CARGO_PKG_VERSION")),
+            Duration::from_secs(default_http_timeout_secs()),
+        )
+        .expect("default client builds")
+    })
+}
+
+/// A client sending `user_agent` that gives up on connecting, and on each whole request, after
+/// `timeout`.
+fn build_http_client(
+    user_agent: &str,
+    timeout: Duration,
+) -> Result<reqwest::Client, reqwest::Error> {
+    reqwest::Client::builder()
+        .user_agent(user_agent)
+        .timeout(timeout)
+        .connect_timeout(timeout)
+        .build()
+}
+
+/// Fetches `url` and parses it as JSON, retrying transient failures.
+async fn get_json_with_retries(url: &str, options: FetchOptions) -> Result<Value, AppError> {
+    let response = fetch_with_retries(url, options).await?;
+    parse_json(url, &response)
+}
+
+/// A successful response, read in full.
+struct FetchedResponse {
+    status: reqwest::StatusCode,
+    content_type: Option<String>,
+    body: String,
+}
+
+/// Fetches `url`, retrying transient failures; error statuses are returned as errors.
+async fn fetch_with_retries(url: &str, options: FetchOptions) -> Result<FetchedResponse, AppError> {
+    let mut attempt = 0;
+    loop {
+        let result = metrics::track("tvmaze", async {
+            let response = http_client().get(url).send().await?.error_for_status()?;
+            let content_type = response
+                .headers()
+                .get(CONTENT_TYPE)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_owned);
+            Ok(FetchedResponse {
+                status: response.status(),
+                content_type,
+                body: response.text().await?,
+            })
+        })
+        .await;
+        match result {
+            Err(err) if attempt < options.retries && error::is_transient_request(&err) => {
+                let delay = backoff_delay(attempt, options.retry_base_delay, &mut rand::rng());
+                eprintln!("retrying {url} in {delay:?}: {err}");
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+            }
+            result => return Ok(result?),
+        }
+    }
+}
+
+/// Parses a response body as JSON.
+///
+/// TVMaze sometimes serves an HTML error page during incidents, so non-JSON responses are
+/// reported with their content type and the start of the body rather than a bare serde error.
+fn parse_json(url: &str, response: &FetchedResponse) -> Result<Value, AppError> {
+    let body = &response.body;
+    if let Some(content_type) = response
+        .content_type
+        .as_deref()
+        .filter(|value| !value.contains("json"))
+    {
+        return Err(AppError::Parse(format!(
+            "expected JSON from {url} but got {content_type}: {}",
+            snippet(body)
+        )));
+    }
+    serde_json::from_str(body).map_err(|err| {
+        AppError::Parse(format!(
+            "invalid JSON from {url} ({err}): {}",
+            snippet(body)
+        ))
+    })
+}
+
+/// The first line or so of `body`, for error messages.
+fn snippet(body: &str) -> String {
+    const MAX_CHARS: usize = 120;
+    let body = body.split_whitespace().collect::<Vec<_>>().join(" ");
+    match body.char_indices().nth(MAX_CHARS) {
+        Some((end, _)) => format!("{}...", &body[..end]),
+        None => body,
+    }
+}
+
+async fn get_show_name(show_id: i32, options: FetchOptions) -> Result<String, AppError> {
+    let show =
+        get_json_with_retries(&format!("https://api.tvmaze.com/shows/{show_id}"), options).await?;
+    show["name"]
+        .as_str()
+        .map(str::to_owned)
+        .ok_or_else(|| AppError::Parse(format!("show {show_id} has no name")))
+}
+
+async fn get_next_episode(
+    show_id: i32,
+    options: FetchOptions,
+) -> Result<Vec<EpisodeLookup>, AppError> {
+    let show = get_json_with_retries(&episodes_url(show_id, options), options).await?;
+    classify_episodes(show_id, &show, options)
+}
+
+/// The show lookup with the embedded episodes `classify_episodes` needs.
+fn episodes_url(show_id: i32, options: FetchOptions) -> String {
+    let mut url = format!(
+        "https://api.tvmaze.com/shows/{show_id}?embed[]=nextepisode&embed[]=previousepisode"
+    );
+    if options.all_episodes {
+        url.push_str("&embed[]=episodes");
+    }
+    url
+}
+
+/// Fetches one show the way a run would, bypassing the database and email, and prints the
+/// embedded episodes along with what the digest would make of them.
+async fn debug_show(config: &Config, options: RunOptions, show_id: i32) -> Result<(), AppError> {
+    let fetch_options = FetchOptions::new(config, options);
+    let url = episodes_url(show_id, fetch_options);
+    println!("GET {url}");
+    let response = fetch_with_retries(&url, fetch_options).await?;
+    println!(
+        "HTTP {} {}",
+        response.status,
+        response
+            .content_type
+            .as_deref()
+            .unwrap_or("(no content type)")
+    );
+    let show = parse_json(&url, &response)?;
+    let embedded = &show["_embedded"];
+    for key in ["previousepisode", "nextepisode"] {
+        match embedded[key]["airstamp"].as_str() {
+            Some(airstamp) => println!("{key}: {} at {airstamp}", embedded[key]["name"]),
+            None => println!("{key}: none"),
+        }
+    }
+    let previous_time = embedded["previousepisode"]["airstamp"]
+        .as_str()
+        .and_then(|airstamp| DateTime::parse_from_rfc3339(airstamp).ok());
+    for lookup in classify_episodes(show_id, &show, fetch_options)? {
+        println!("{}", describe_lookup(&lookup, previous_time));
+    }
+    Ok(())
+}
+
+/// One line saying where a lookup lands in the digest and, for listed shows, which embedded
+/// episode it came from.
+fn describe_lookup(lookup: &EpisodeLookup, previous_time: Option<DateTime<FixedOffset>>) -> String {
+    match lookup {
+        EpisodeLookup::Scheduled(show) if previous_time == Some(show.show_time.fixed_offset()) => {
+            format!("listed (previous episode, airing today): {show}")
+        }
+        EpisodeLookup::Scheduled(show) => format!("listed (upcoming episode): {show}"),
+        EpisodeLookup::RecentlyAired(show) => format!("recently aired: {show}"),
+        EpisodeLookup::Stalled(_) => "no upcoming episode".to_owned(),
+    }
+}
+
+/// Decides where a show with embedded previous/next episodes belongs in the digest.
+///
+/// An episode that aired today is listed on its own. One that aired within the previous
+/// `lookback_days` local days is reported as recently aired, alongside the next episode if any.
+/// With `all_episodes`, every embedded episode airing from today through the future day limit is
+/// listed, falling back to the next episode when none do.
+fn classify_episodes(
+    show_id: i32,
+    show: &Value,
+    options: FetchOptions,
+) -> Result<Vec<EpisodeLookup>, AppError> {
+    let show_name = show["name"]
+        .as_str()
+        .ok_or_else(|| AppError::Parse(format!("show {show_id} has no name")))?;
+    let image = show["image"]["medium"].as_str();
+    let stalled = || {
+        EpisodeLookup::Stalled(StalledShow {
+            id: show_id,
+            name: show_name.to_owned(),
+        })
+    };
+    let embedded = &show["_embedded"];
+    if !embedded.is_object() {
+        return Ok(vec![stalled()]);
+    }
+    let today = options.now.date_naive();
+    let mut lookups = vec![];
+    if let Some(prev_episode) = embedded_episode(show_id, embedded, "previousepisode") {
+        let prev_show = parse_show(show_id, show_name, image, prev_episode);
+        let aired_on = prev_show.show_time.date_naive();
+        let already_aired = prev_show.show_time <= options.now;
+        if aired_on == today {
+            // The full episode list already includes today's airings.
+            let listed = !(options.skip_aired && already_aired);
+            if listed && !options.all_episodes {
+                return Ok(vec![EpisodeLookup::Scheduled(prev_show)]);
+            }
+        } else if aired_on < today
+            && today
+                .checked_sub_days(Days::new(options.lookback_days))
+                .is_some_and(|cutoff| aired_on >= cutoff)
+        {
+            lookups.push(EpisodeLookup::RecentlyAired(prev_show));
+        }
+    }
+    if options.all_episodes {
+        let last_day = today.checked_add_days(Days::new(FUTURE_DAY_LIMIT)).unwrap();
+        let upcoming: Vec<Show> = embedded["episodes"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(Value::as_object)
+            .map(|episode| parse_show(show_id, show_name, image, episode))
+            .filter(|show| (today..=last_day).contains(&show.show_time.date_naive()))
+            .filter(|show| !(options.skip_aired && show.show_time <= options.now))
+            .collect();
+        if !upcoming.is_empty() {
+            lookups.extend(upcoming.into_iter().map(EpisodeLookup::Scheduled));
+            return Ok(lookups);
+        }
+    }
+    let Some(next_episode) = embedded_episode(show_id, embedded, "nextepisode") else {
+        if lookups.is_empty() {
+            lookups.push(stalled());
+        }
+        return Ok(lookups);
+    };
+    let next_show = parse_show(show_id, show_name, image, next_episode);
+    lookups.push(EpisodeLookup::Scheduled(next_show));
+    Ok(lookups)
+}
+
+/// The embedded episode under `key`, or `None` when there is none.
+///
+/// Anything other than an object is logged as an anomaly and treated as no episode, so an
+/// unexpected payload can't take down the run.
+fn embedded_episode<'a>(
+    show_id: i32,
+    embedded: &'a Value,
+    key: &str,
+) -> Option<&'a Map<String, Value>> {
+    match &embedded[key] {
+        Value::Null => None,
+        Value::Object(episode) => Some(episode),
+        other => {
+            eprintln!(
+                "show {show_id} has an unexpected {key}, ignoring it: {}",
+                snippet(&other.to_string())
+            );
+            None
+        }
+    }
+}
+
+/// Collects the tracked shows' airings from TVMaze's daily schedules, today through the future
+/// day limit.
+///
+/// Both the broadcast schedule for `country` and the streaming schedule are read, so the number
+/// of requests doesn't grow with the watchlist. Shows with nothing in the window are left out:
+/// the schedule can't tell a stalled show from one that returns next month. Past days aren't
+/// read either, so there is never anything recently aired and `lookback_days` doesn't apply.
+async fn get_shows_from_schedule(
+    show_ids: &[i32],
+    country: &str,
+    options: FetchOptions,
+) -> Result<Digest, AppError> {
+    let today = options.now.date_naive();
+    let mut requests = vec![];
+    for offset in 0..=FUTURE_DAY_LIMIT {
+        let date = today.checked_add_days(Days::new(offset)).unwrap();
+        let date = date.format("%Y-%m-%d");
+        for url in [
+            format!("https://api.tvmaze.com/schedule?country={country}&date={date}"),
+            format!("https://api.tvmaze.com/schedule/web?date={date}"),
+        ] {
+            requests.push(tokio::spawn(async move {
+                get_json_with_retries(&url, options).await
+            }));
+        }
+    }
+    let mut entries = vec![];
+    for request in requests {
+        let schedule = request.await.unwrap_or_else(|err| {
+            Err(AppError::Http(format!("schedule fetch task failed: {err}")))
+        })?;
+        match schedule {
+            Value::Array(day) => entries.extend(day),
+            _ => return Err(AppError::Parse("schedule is not a list".to_owned())),
+        }
+    }
+    let tracked: HashSet<i32> = show_ids.iter().copied().collect();
+    let shows = shows_from_schedule(&entries, &tracked, options);
+    Ok(Digest {
+        shows,
+        recently_aired: vec![],
+        stalled: vec![],
+        failures: vec![],
+        removed: vec![],
+    })
+}
+
+/// Picks the tracked shows' episodes out of schedule entries, sorted by air time.
+///
+/// Broadcast entries carry their show under `show`, streaming ones under `_embedded.show`. As
+/// with per-show lookups, only each show's next airing is kept unless `all_episodes` is set.
+fn shows_from_schedule(
+    entries: &[Value],
+    tracked: &HashSet<i32>,
+    options: FetchOptions,
+) -> Vec<Show> {
+    let mut shows: Vec<Show> = entries
+        .iter()
+        .filter_map(|entry| {
+            let episode = entry.as_object()?;
+            let show = entry
+                .get("show")
+                .or_else(|| entry.get("_embedded")?.get("show"))?;
+            let show_id = i32::try_from(show["id"].as_i64()?).ok()?;
+            if !tracked.contains(&show_id) {
+                return None;
+            }
+            let name = show["name"].as_str()?;
+            Some(parse_show(
+                show_id,
+                name,
+                show["image"]["medium"].as_str(),
+                episode,
+            ))
+        })
+        .filter(|show| show.show_time.date_naive() >= options.now.date_naive())
+        .filter(|show| !(options.skip_aired && show.show_time <= options.now))
+        .collect();
+    shows.sort_by_key(|show| show.show_time);
+    if !options.all_episodes {
+        let mut seen = HashSet::new();
+        shows.retain(|show| seen.insert(show.id));
+    }
+    shows
+}
+
+/// Episode counts per season, by show, kept for the life of the process.
+static SEASON_LENGTHS: Mutex<BTreeMap<i32, BTreeMap<i32, i32>>> = Mutex::new(BTreeMap::new());
+
+/// Flags season finales among the digest's episodes.
+///
+/// Each show's season lengths are fetched once and cached. Shows whose seasons can't be looked
+/// up simply get no badge.
+async fn mark_finales(digest: &mut Digest, options: FetchOptions) {
+    let show_ids: Vec<i32> = {
+        let cache = SEASON_LENGTHS.lock().unwrap();
+        let mut show_ids: Vec<i32> = digest
+            .shows
+            .iter()
+            .chain(&digest.recently_aired)
+            .filter(|show| show.number.is_some() && !cache.contains_key(&show.id))
+            .map(|show| show.id)
+            .collect();
+        show_ids.sort_unstable();
+        show_ids.dedup();
+        show_ids
+    };
+    let lookups: Vec<_> = show_ids
+        .into_iter()
+        .map(|show_id| (show_id, tokio::spawn(get_season_lengths(show_id, options))))
+        .collect();
+    for (show_id, lookup) in lookups {
+        match lookup.await {
+            Ok(Ok(lengths)) => {
+                SEASON_LENGTHS.lock().unwrap().insert(show_id, lengths);
+            }
+            Ok(Err(err)) => eprintln!("failed to look up seasons of show {show_id}: {err}"),
+            Err(err) => eprintln!("season lookup task for show {show_id} failed: {err}"),
+        }
+    }
+    let cache = SEASON_LENGTHS.lock().unwrap();
+    for show in digest.shows.iter_mut().chain(&mut digest.recently_aired) {
+        if let Some(lengths) = cache.get(&show.id) {
+            show.finale = is_finale(show, lengths);
+        }
+    }
+}
+
+fn is_finale(show: &Show, season_lengths: &BTreeMap<i32, i32>) -> bool {
+    match (show.season, show.number) {
+        (Some(season), Some(number)) => season_lengths.get(&season) == Some(&number),
+        _ => false,
+    }
+}
+
+/// Maps each season number to its planned episode count, skipping seasons TVMaze has no count
+/// for yet.
+async fn get_season_lengths(
+    show_id: i32,
+    options: FetchOptions,
+) -> Result<BTreeMap<i32, i32>, AppError> {
+    let url = format!("https://api.tvmaze.com/shows/{show_id}/seasons");
+    let seasons = get_json_with_retries(&url, options).await?;
+    let seasons = seasons
+        .as_array()
+        .ok_or_else(|| AppError::Parse(format!("seasons of show {show_id} are not a list")))?;
+    Ok(seasons
+        .iter()
+        .filter_map(Value::as_object)
+        .filter_map(|season| {
+            Some((
+                int_field(season, "number")?,
+                int_field(season, "episodeOrder")?,
+            ))
+        })
+        .collect())
+}
+
+/// Drops repeated show IDs, keeping the first occurrence of each.
+fn dedupe_show_ids(show_ids: Vec<i32>) -> Vec<i32> {
+    let mut seen = HashSet::new();
+    show_ids
+        .into_iter()
+        .filter(|show_id| seen.insert(*show_id))
+        .collect()
+}
+
+/// Looks up every show concurrently, keeping each show's failure alongside the successes.
+async fn get_shows_parallel(show_ids: Vec<i32>, options: FetchOptions) -> Digest {
+    let mut show_handles = vec![];
+    for show_id in show_ids {
+        show_handles.push((show_id, tokio::spawn(get_next_episode(show_id, options))))
     }
     let mut shows = vec![];
-    for show_handle in show_handles {
-        if let Some(show) = show_handle.await?.unwrap() {
-            shows.push(show)
+    let mut recently_aired = vec![];
+    let mut stalled = vec![];
+    let mut failures = vec![];
+    for (show_id, show_handle) in show_handles {
+        let lookups = show_handle
+            .await
+            .unwrap_or_else(|err| Err(AppError::Http(format!("show fetch task failed: {err}"))));
+        match lookups {
+            Ok(lookups) => {
+                for lookup in lookups {
+                    match lookup {
+                        EpisodeLookup::Scheduled(show) => shows.push(show),
+                        EpisodeLookup::RecentlyAired(show) => recently_aired.push(show),
+                        EpisodeLookup::Stalled(show) => stalled.push(show),
+                    }
+                }
+            }
+            Err(error) => failures.push(FetchFailure { show_id, error }),
         }
     }
-    shows.sort_by(|a, b| a.show_time.cmp(&b.show_time));
-    Ok(shows)
+    shows.sort_by_key(|show| show.show_time);
+    recently_aired.sort_by_key(|show| show.show_time);
+    Digest {
+        shows,
+        recently_aired,
+        stalled,
+        failures,
+        removed: vec![],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{Datelike, TimeZone};
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    const SITE_URL: &str = "https://tv.example.com";
+
+    fn test_config() -> Config {
+        serde_json::from_value(serde_json::json!({
+            "smtp_server": "smtp.invalid",
+            "smtp_host": "smtp.invalid",
+            "smtp_user": "user",
+            "smtp_password": "password",
+            "from_email": "tv@example.com",
+            "site_url": SITE_URL,
+        }))
+        .unwrap()
+    }
+
+    fn today() -> NaiveDate {
+        NaiveDate::from_ymd_opt(2024, 3, 4).unwrap()
+    }
+
+    fn render_options() -> RenderOptions<'static> {
+        RenderOptions {
+            site_url: SITE_URL,
+            greeting: None,
+            messages: &locale::EN,
+            today: today(),
+            future_limit: None,
+            show_stalled: false,
+            mode: DigestMode::Daily,
+            layout: Layout::Pre,
+            sort_order: SortOrder::Asc,
+            group_by: GroupBy::None,
+        }
+    }
+
+    fn digest(shows: Vec<Show>) -> Digest {
+        Digest {
+            shows,
+            recently_aired: vec![],
+            stalled: vec![],
+            failures: vec![],
+            removed: vec![],
+        }
+    }
+
+    fn show(id: i32, name: &str, episode_name: &str, show_time: DateTime<Local>) -> Show {
+        Show {
+            id,
+            episode_name: episode_name.to_owned(),
+            ..Show::test(name, show_time)
+        }
+    }
+
+    fn at(day: u32, hour: u32, minute: u32) -> DateTime<Local> {
+        Local
+            .with_ymd_and_hms(2024, 3, day, hour, minute, 0)
+            .unwrap()
+    }
+
+    fn channel(user_id: &str, email: &str) -> NotificationChannel {
+        NotificationChannel {
+            user_id: user_id.to_owned(),
+            channel_type: "email".to_owned(),
+            destination: email.to_owned(),
+            user_name: None,
+        }
+    }
+
+    fn read_audit(path: &std::path::Path) -> Vec<Value> {
+        fs::read_to_string(path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn build_email_html_splits_today_and_future_shows() {
+        let shows = vec![
+            show(1, "Tonight Show", "Pilot", at(4, 20, 0)),
+            show(2, "Later Show", "Finale", at(6, 21, 30)),
+        ];
+
+        let html = build_email_html(&digest(shows), &render_options());
+
+        assert_eq!(
+            html,
+            "<pre><b>Today's shows:<br />\
+             Mon. Mar. 04  8:00 PM: <a href=\"https://www.tvmaze.com/shows/1\">Tonight Show</a> (Pilot)<br />\
+             </b><br /><br />\
+             Future shows:<br />\
+             Wed. Mar. 06  9:30 PM: <a href=\"https://www.tvmaze.com/shows/2\">Later Show</a> (Finale)<br />\
+             <br /><br />Manage subscriptions on <a href=\"https://tv.example.com\">TV Notifier UI</a>\
+             </pre>"
+        );
+    }
+
+    #[test]
+    fn build_email_html_without_today_shows_uses_fallback() {
+        let shows = vec![show(2, "Later Show", "Finale", at(6, 21, 30))];
+
+        let html = build_email_html(&digest(shows), &render_options());
+
+        assert!(html.starts_with("<pre><b>Today's shows:<br /><i>Nothing airing today.</i></b>"));
+        assert!(html.contains("Future shows:<br />"));
+        assert!(html.contains("<a href=\"https://www.tvmaze.com/shows/2\">Later Show</a>"));
+    }
+
+    #[test]
+    fn build_email_html_with_no_shows_has_only_fallback_and_footer() {
+        let html = build_email_html(&digest(vec![]), &render_options());
+
+        assert_eq!(
+            html,
+            "<pre><b>Today's shows:<br /><i>Nothing airing today.</i></b><br /><br />\
+             <br /><br />Manage subscriptions on <a href=\"https://tv.example.com\">TV Notifier UI</a>\
+             </pre>"
+        );
+    }
+
+    #[test]
+    fn send_email_without_subscribers_skips_sending() {
+        let shows = vec![show(1, "Tonight Show", "Pilot", at(4, 20, 0))];
+
+        let sent = send_email(
+            &digest(shows),
+            &test_config(),
+            &BTreeMap::new(),
+            &render_options(),
+        )
+        .unwrap()
+        .sent;
+
+        assert_eq!(sent, 0);
+    }
+
+    #[test]
+    fn send_email_with_skip_empty_sends_nothing_on_a_quiet_day() {
+        let outbox = std::env::temp_dir().join(format!("tvnotifier-empty-{}", process::id()));
+        let config = Config {
+            outbox_dir: Some(outbox.clone()),
+            skip_empty: true,
+            ..test_config()
+        };
+        let recipients = email_recipients(vec![channel("1", "a@example.com")]);
+        let far_off = vec![show(1, "Later Show", "Premiere", at(20, 20, 0))];
+
+        let sent = send_email(&digest(far_off), &config, &recipients, &render_options())
+            .unwrap()
+            .sent;
+
+        assert_eq!(sent, 0);
+        assert!(!outbox.exists());
+    }
+
+    #[test]
+    fn send_email_with_outbox_writes_eml_files() {
+        let outbox = std::env::temp_dir().join(format!("tvnotifier-outbox-{}", process::id()));
+        let config = Config {
+            outbox_dir: Some(outbox.clone()),
+            manage_url_template: Some("https://tv.example.com/users/{user_id}".to_owned()),
+            ..test_config()
+        };
+        let recipients = email_recipients(vec![
+            NotificationChannel {
+                user_name: Some("Ada".to_owned()),
+                ..channel("1", "a@example.com")
+            },
+            channel("2", "b@example.com"),
+        ]);
+        let shows = vec![show(1, "Tonight Show", "Pilot", at(4, 20, 0))];
+
+        let sent = send_email(&digest(shows), &config, &recipients, &render_options())
+            .unwrap()
+            .sent;
+
+        let messages: Vec<String> = fs::read_dir(&outbox)
+            .unwrap()
+            // Undo quoted-printable soft line breaks so long lines can be matched.
+            .map(|entry| {
+                fs::read_to_string(entry.unwrap().path())
+                    .unwrap()
+                    .replace("=\r\n", "")
+            })
+            .collect();
+        fs::remove_dir_all(&outbox).unwrap();
+        assert_eq!(sent, 2);
+        assert_eq!(messages.len(), 2);
+        assert!(messages
+            .iter()
+            .all(|message| message.contains("Subject: Upcoming shows for Mon. Mar. 04")));
+        let message_to = |address: &str| {
+            messages
+                .iter()
+                .find(|message| message.contains(&format!("To: {address}")))
+                .unwrap()
+        };
+        assert!(message_to("a@example.com").contains("Hi Ada,"));
+        assert!(message_to("a@example.com").contains("https://tv.example.com/users/1"));
+        assert!(message_to("b@example.com").contains("Hi there,"));
+        assert!(message_to("b@example.com").contains("https://tv.example.com/users/2"));
+    }
+
+    #[test]
+    fn backoff_delay_stays_within_exponential_bounds() {
+        let base = Duration::from_millis(100);
+        let mut rng = StdRng::seed_from_u64(7);
+        for attempt in 0..6 {
+            for _ in 0..50 {
+                let delay = backoff_delay(attempt, base, &mut rng);
+                assert!(
+                    delay <= base * 2_u32.pow(attempt),
+                    "{delay:?} on attempt {attempt}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn backoff_delay_is_jittered_and_reproducible_with_a_seed() {
+        let base = Duration::from_millis(1000);
+        let delays = |seed| {
+            let mut rng = StdRng::seed_from_u64(seed);
+            (0..10)
+                .map(|_| backoff_delay(3, base, &mut rng))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(delays(1), delays(1));
+        assert_ne!(delays(1), delays(2));
+        assert!(delays(1).windows(2).any(|pair| pair[0] != pair[1]));
+    }
+
+    #[test]
+    fn weekly_mode_groups_by_day_and_drops_whole_days_over_the_limit() {
+        let shows = vec![
+            show(1, "Monday Show", "One", at(4, 20, 0)),
+            show(2, "Tuesday Show", "Two", at(5, 20, 0)),
+            show(3, "Tuesday Late Show", "Three", at(5, 23, 0)),
+        ];
+        let options = RenderOptions {
+            mode: DigestMode::Weekly,
+            future_limit: Some(2),
+            ..render_options()
+        };
+
+        let html = build_email_html(&digest(shows), &options);
+
+        assert!(
+            html.starts_with("<pre><b>This week in TV:</b><br /><br /><b>Mon. Mar. 04</b><br />")
+        );
+        assert!(html.contains("Monday Show"));
+        assert!(!html.contains("Tue. Mar. 05"));
+        assert!(html.contains("<i>+2 more</i>"));
+    }
+
+    #[test]
+    fn descending_daily_digest_groups_future_days_above_today() {
+        let shows = vec![
+            show(1, "Monday Show", "One", at(4, 20, 0)),
+            show(2, "Tuesday Show", "Two", at(5, 20, 0)),
+            show(3, "Wednesday Early Show", "Three", at(6, 19, 0)),
+            show(4, "Wednesday Late Show", "Four", at(6, 23, 0)),
+        ];
+        let options = RenderOptions {
+            sort_order: SortOrder::Desc,
+            group_by: GroupBy::Day,
+            ..render_options()
+        };
+
+        let html = build_email_html(&digest(shows), &options);
+
+        let position = |text| html.find(text).unwrap();
+        assert!(html.starts_with("<pre>Future shows:<br /><br /><b>Wed. Mar. 06</b><br />"));
+        assert!(position("Wednesday Late Show") < position("Wednesday Early Show"));
+        assert!(position("Wednesday Early Show") < position("Tue. Mar. 05"));
+        assert!(position("Tuesday Show") < position("Today's shows:"));
+        assert!(position("Today's shows:") < position("Monday Show"));
+    }
+
+    #[test]
+    fn grouped_daily_digest_drops_whole_days_over_the_limit() {
+        let shows = vec![
+            show(1, "Tuesday Show", "One", at(5, 20, 0)),
+            show(2, "Wednesday Early Show", "Two", at(6, 19, 0)),
+            show(3, "Wednesday Late Show", "Three", at(6, 23, 0)),
+        ];
+        let options = RenderOptions {
+            group_by: GroupBy::Day,
+            future_limit: Some(2),
+            ..render_options()
+        };
+
+        let html = build_email_html(&digest(shows), &options);
+
+        assert!(html.contains("<b>Tue. Mar. 05</b><br />"));
+        assert!(!html.contains("Wed. Mar. 06"));
+        assert!(!html.contains("Wednesday Early Show"));
+        assert!(html.contains("<i>+2 more</i>"));
+    }
+
+    #[test]
+    fn quiet_hours_wrapping_midnight() {
+        let quiet_hours: QuietHours =
+            serde_json::from_str(r#"{"start": "22:00", "end": "07:00"}"#).unwrap();
+        let time = |hour, minute| NaiveTime::from_hms_opt(hour, minute, 0).unwrap();
+
+        assert!(quiet_hours.contains(time(22, 0)));
+        assert!(quiet_hours.contains(time(23, 59)));
+        assert!(quiet_hours.contains(time(3, 0)));
+        assert!(!quiet_hours.contains(time(7, 0)));
+        assert!(!quiet_hours.contains(time(12, 0)));
+        assert!(!quiet_hours.contains(time(21, 59)));
+    }
+
+    #[test]
+    fn quiet_hours_within_one_day() {
+        let quiet_hours: QuietHours =
+            serde_json::from_str(r#"{"start": "01:00", "end": "06:30"}"#).unwrap();
+        let time = |hour, minute| NaiveTime::from_hms_opt(hour, minute, 0).unwrap();
+
+        assert!(quiet_hours.contains(time(1, 0)));
+        assert!(quiet_hours.contains(time(6, 29)));
+        assert!(!quiet_hours.contains(time(6, 30)));
+        assert!(!quiet_hours.contains(time(23, 0)));
+    }
+
+    #[test]
+    fn runtime_is_shown_when_known_and_omitted_when_null() {
+        let episode = |runtime: Value| {
+            let details = serde_json::json!({
+                "name": "Pilot",
+                "airstamp": "2024-03-05T01:00:00+00:00",
+                "runtime": runtime,
+            });
+            parse_show(1, "Tonight Show", None, details.as_object().unwrap())
+        };
+
+        let timed = episode(serde_json::json!(45));
+        assert_eq!(timed.runtime, Some(45));
+        assert!(timed.html(&locale::EN).ends_with("(Pilot) (45 min)"));
+        assert!(timed.to_string().ends_with("(Pilot) (45 min)"));
+
+        let untimed = episode(Value::Null);
+        assert_eq!(untimed.runtime, None);
+        assert!(untimed.html(&locale::EN).ends_with("(Pilot)"));
+    }
+
+    fn fetch_options(lookback_days: u64) -> FetchOptions {
+        FetchOptions {
+            now: at(4, 12, 0),
+            skip_aired: false,
+            retries: 0,
+            retry_base_delay: Duration::ZERO,
+            lookback_days,
+            all_episodes: false,
+        }
+    }
+
+    fn show_with_episodes(previous: &str, next: Option<&str>) -> Value {
+        let episode =
+            |airstamp: &str| serde_json::json!({ "name": "Episode", "airstamp": airstamp });
+        serde_json::json!({
+            "name": "Tracked Show",
+            "_embedded": {
+                "previousepisode": episode(previous),
+                "nextepisode": next.map(episode),
+            },
+        })
+    }
+
+    #[test]
+    fn lookback_reports_recent_episodes_alongside_the_next_one() {
+        let previous = at(2, 20, 0).to_rfc3339();
+        let next = at(9, 20, 0).to_rfc3339();
+        let show = show_with_episodes(&previous, Some(&next));
+
+        let lookups = classify_episodes(1, &show, fetch_options(2)).unwrap();
+
+        assert!(matches!(
+            lookups.as_slice(),
+            [EpisodeLookup::RecentlyAired(recent), EpisodeLookup::Scheduled(upcoming)]
+                if recent.show_time == at(2, 20, 0) && upcoming.show_time == at(9, 20, 0)
+        ));
+    }
+
+    #[test]
+    fn lookback_defaults_to_today_only() {
+        let previous = at(3, 20, 0).to_rfc3339();
+        let show = show_with_episodes(&previous, None);
+
+        let lookups = classify_episodes(1, &show, fetch_options(0)).unwrap();
+
+        assert!(matches!(lookups.as_slice(), [EpisodeLookup::Stalled(_)]));
+    }
+
+    #[test]
+    fn malformed_embedded_episodes_are_treated_as_missing() {
+        let next = at(6, 20, 0).to_rfc3339();
+        let show = |previous: Value, next: Value| {
+            serde_json::json!({
+                "name": "Tracked Show",
+                "_embedded": { "previousepisode": previous, "nextepisode": next },
+            })
+        };
+
+        let array_previous = show(
+            serde_json::json!([]),
+            serde_json::json!({ "airstamp": next }),
+        );
+        let lookups = classify_episodes(1, &array_previous, fetch_options(0)).unwrap();
+        assert!(matches!(
+            lookups.as_slice(),
+            [EpisodeLookup::Scheduled(upcoming)]
+                if upcoming.show_time == at(6, 20, 0) && upcoming.episode_name.is_empty()
+        ));
+
+        let string_next = show(Value::Null, serde_json::json!("soon"));
+        let lookups = classify_episodes(1, &string_next, fetch_options(0)).unwrap();
+        assert!(matches!(lookups.as_slice(), [EpisodeLookup::Stalled(_)]));
+
+        let array_next = show(
+            serde_json::json!(7),
+            serde_json::json!([{ "airstamp": next }]),
+        );
+        let lookups = classify_episodes(1, &array_next, fetch_options(0)).unwrap();
+        assert!(matches!(lookups.as_slice(), [EpisodeLookup::Stalled(_)]));
+    }
+
+    #[test]
+    fn dedupe_show_ids_keeps_first_occurrence_order() {
+        assert_eq!(
+            dedupe_show_ids(vec![82, 431, 82, 1371, 431, 82]),
+            vec![82, 431, 1371]
+        );
+        assert_eq!(dedupe_show_ids(vec![]), Vec::<i32>::new());
+    }
+
+    #[test]
+    fn all_episodes_lists_every_airing_in_the_window() {
+        let episode = |day, hour| serde_json::json!({ "name": format!("Day {day}"), "airstamp": at(day, hour, 0).to_rfc3339() });
+        let show = serde_json::json!({
+            "name": "Daily Show",
+            "_embedded": {
+                "previousepisode": episode(4, 8),
+                "nextepisode": episode(5, 20),
+                "episodes": [episode(3, 20), episode(4, 8), episode(4, 20), episode(5, 20), episode(20, 20)],
+            },
+        });
+        let options = FetchOptions {
+            all_episodes: true,
+            ..fetch_options(0)
+        };
+
+        let lookups = classify_episodes(1, &show, options).unwrap();
+
+        let names: Vec<&str> = lookups
+            .iter()
+            .map(|lookup| match lookup {
+                EpisodeLookup::Scheduled(show) => show.episode_name.as_str(),
+                _ => panic!("unexpected lookup"),
+            })
+            .collect();
+        assert_eq!(names, ["Day 4", "Day 4", "Day 5"]);
+
+        let skip_aired = FetchOptions {
+            skip_aired: true,
+            ..options
+        };
+        assert_eq!(classify_episodes(1, &show, skip_aired).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn responsive_layout_keeps_links_and_footer_without_pre() {
+        let shows = vec![show(1, "Tonight Show", "Pilot", at(4, 20, 0))];
+        let options = RenderOptions {
+            layout: Layout::Responsive,
+            ..render_options()
+        };
+
+        let html = build_email_html(&digest(shows), &options);
+
+        assert!(!html.contains("<pre>"));
+        assert!(html.starts_with("<div style="));
+        assert!(html.ends_with("</div>"));
+        assert!(html.contains("<a href=\"https://www.tvmaze.com/shows/1\">Tonight Show</a>"));
+        assert!(html.contains("<a href=\"https://tv.example.com\">TV Notifier UI</a>"));
+        assert!(!html.contains("<b>"));
+        assert!(html.contains("<div style=\"font-weight: bold\">Today's shows:<br /><div "));
+    }
+
+    #[test]
+    fn schedule_strategy_matches_per_show_lookups() {
+        let episode = |name: &str, day, hour| serde_json::json!({ "name": name, "airstamp": at(day, hour, 0).to_rfc3339(), "runtime": 30 });
+        let tracked_show =
+            |id, name: &str| serde_json::json!({ "id": id, "name": name, "image": null });
+        let mut broadcast = episode("Pilot", 4, 20);
+        broadcast["show"] = tracked_show(1, "Broadcast Show");
+        let mut streaming = episode("Drop", 6, 9);
+        streaming["_embedded"] = serde_json::json!({ "show": tracked_show(2, "Streaming Show") });
+        let mut rerun = episode("Second", 5, 20);
+        rerun["show"] = tracked_show(1, "Broadcast Show");
+        let mut untracked = episode("Other", 5, 21);
+        untracked["show"] = tracked_show(3, "Untracked Show");
+        let entries = [rerun, broadcast, untracked, streaming];
+        let tracked = HashSet::from([1, 2]);
+
+        let from_schedule = shows_from_schedule(&entries, &tracked, fetch_options(0));
+
+        let per_show: Vec<Show> = [
+            (1, "Broadcast Show", episode("Pilot", 4, 20)),
+            (2, "Streaming Show", episode("Drop", 6, 9)),
+        ]
+        .into_iter()
+        .flat_map(|(id, name, next)| {
+            let show = serde_json::json!({ "name": name, "_embedded": { "nextepisode": next } });
+            classify_episodes(id, &show, fetch_options(0)).unwrap()
+        })
+        .map(|lookup| match lookup {
+            EpisodeLookup::Scheduled(show) => show,
+            _ => panic!("unexpected lookup"),
+        })
+        .collect();
+        let key = |show: &Show| {
+            (
+                show.id,
+                show.name.clone(),
+                show.episode_name.clone(),
+                show.show_time,
+                show.runtime,
+            )
+        };
+        assert_eq!(
+            from_schedule.iter().map(key).collect::<Vec<_>>(),
+            per_show.iter().map(key).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn compare_with_previous_marks_new_and_dropped_episodes() {
+        let entry = |show_id, episode_name: &str, show_time| DigestEntry {
+            show_id,
+            show_name: format!("Show {show_id}"),
+            episode_name: episode_name.to_owned(),
+            show_time,
+        };
+        let previous = vec![
+            entry(1, "Pilot", at(4, 20, 0)),
+            entry(2, "Cancelled", at(6, 21, 0)),
+            entry(3, "Aired", at(3, 21, 0)),
+        ];
+        let mut digest = digest(vec![
+            show(1, "Show 1", "Pilot", at(4, 20, 0)),
+            show(4, "Show 4", "Premiere", at(5, 20, 0)),
+        ]);
+
+        compare_with_previous(&mut digest, previous, at(4, 12, 0));
+
+        assert!(!digest.shows[0].new);
+        assert!(digest.shows[1].new);
+        assert!(digest.shows[1]
+            .html(&locale::EN)
+            .ends_with("(Premiere) <b>NEW</b>"));
+        let removed: Vec<&str> = digest
+            .removed
+            .iter()
+            .map(|entry| entry.episode_name.as_str())
+            .collect();
+        assert_eq!(removed, ["Cancelled"]);
+        let html = build_email_html(&digest, &render_options());
+        assert!(html.contains("No longer scheduled:<br /><a href=\"https://www.tvmaze.com/shows/2\">Show 2</a> (Cancelled)"));
+    }
+
+    #[test]
+    fn describe_lookup_tells_todays_previous_episode_from_the_next() {
+        let previous_time = at(4, 20, 0).fixed_offset();
+        let airing_today = EpisodeLookup::Scheduled(show(1, "Show", "Today", at(4, 20, 0)));
+        let upcoming = EpisodeLookup::Scheduled(show(1, "Show", "Later", at(6, 20, 0)));
+        let stalled = EpisodeLookup::Stalled(StalledShow {
+            id: 1,
+            name: "Show".to_owned(),
+        });
+
+        assert!(describe_lookup(&airing_today, Some(previous_time))
+            .starts_with("listed (previous episode, airing today): "));
+        assert!(describe_lookup(&upcoming, Some(previous_time))
+            .starts_with("listed (upcoming episode): "));
+        assert_eq!(describe_lookup(&stalled, None), "no upcoming episode");
+    }
+
+    #[test]
+    fn mark_reschedules_notes_the_previous_air_time() {
+        let previous = vec![
+            DigestEntry {
+                show_id: 1,
+                show_name: "Show 1".to_owned(),
+                episode_name: "Pilot".to_owned(),
+                show_time: at(4, 20, 0),
+            },
+            DigestEntry {
+                show_id: 2,
+                show_name: "Show 2".to_owned(),
+                episode_name: "Pilot".to_owned(),
+                show_time: at(5, 21, 0),
+            },
+        ];
+        let mut digest = digest(vec![
+            show(1, "Show 1", "Pilot", at(6, 20, 0)),
+            show(2, "Show 2", "Pilot", at(5, 21, 0)),
+        ]);
+
+        mark_reschedules(&mut digest, &previous);
+
+        assert_eq!(digest.shows[0].rescheduled_from, Some(at(4, 20, 0)));
+        assert!(digest.shows[0]
+            .html(&locale::EN)
+            .ends_with("(Pilot) <i>rescheduled (was Mon. Mar. 04  8:00 PM)</i>"));
+        assert_eq!(digest.shows[1].rescheduled_from, None);
+    }
+
+    #[test]
+    fn compare_with_previous_without_history_marks_nothing() {
+        let mut digest = digest(vec![show(1, "Show 1", "Pilot", at(4, 20, 0))]);
+
+        compare_with_previous(&mut digest, vec![], at(4, 12, 0));
+
+        assert!(!digest.shows[0].new);
+        assert!(digest.removed.is_empty());
+    }
+
+    #[test]
+    fn build_email_html_buckets_shows_around_day_boundaries() {
+        let shows = vec![
+            show(1, "Late Show", "Just Before Midnight", at(4, 23, 59)),
+            show(2, "Early Show", "Just After Midnight", at(5, 0, 1)),
+            show(3, "Last Day Show", "On The Limit", at(11, 23, 0)),
+            show(4, "Too Far Show", "Past The Limit", at(12, 0, 30)),
+        ];
+
+        let html = build_email_html(&digest(shows), &render_options());
+
+        let (today, future) = html.split_once("Future shows:").unwrap();
+        assert!(today.contains("Just Before Midnight"));
+        assert!(!today.contains("Just After Midnight"));
+        assert!(future.contains("Just After Midnight"));
+        assert!(future.contains("On The Limit"));
+        assert!(!html.contains("Past The Limit"));
+    }
+
+    #[test]
+    fn build_email_html_excludes_shows_with_a_default_air_time() {
+        let details = serde_json::json!({ "name": "Unscheduled", "airstamp": null });
+        let undated = parse_show(1, "Undated Show", None, details.as_object().unwrap());
+        assert_eq!(undated.show_time.year(), 1970);
+        let shows = vec![undated, show(2, "Tonight Show", "Pilot", at(4, 20, 0))];
+
+        let html = build_email_html(&digest(shows), &render_options());
+
+        assert!(!html.contains("Undated Show"));
+        assert!(html.starts_with("<pre><b>Today's shows:<br />Mon. Mar. 04  8:00 PM"));
+    }
+
+    #[test]
+    fn test_show_defaults_render_without_optional_details() {
+        let plain = Show::test("Tonight Show", at(4, 20, 0));
+        let detailed = Show {
+            runtime: Some(45),
+            new: true,
+            ..Show::test("Tonight Show", at(4, 20, 0))
+        };
+
+        assert_eq!(
+            plain.to_string(),
+            "Mon. Mar. 04  8:00 PM: Tonight Show (Episode)"
+        );
+        assert!(detailed
+            .html(&locale::EN)
+            .ends_with("(Episode) (45 min) <b>NEW</b>"));
+    }
+
+    #[test]
+    fn html_escapes_show_and_episode_names() {
+        let show = show(1, "Tom & Jerry", "<Cat> \"Trap\"", at(4, 20, 0));
+
+        assert!(show
+            .html(&locale::EN)
+            .ends_with(">Tom &amp; Jerry</a> (&lt;Cat&gt; &quot;Trap&quot;)"));
+        assert!(show.to_string().ends_with("Tom & Jerry (<Cat> \"Trap\")"));
+    }
+
+    #[test]
+    fn path_segment_encoding_keeps_only_unreserved_bytes() {
+        assert_eq!(encode_path_segment("user-1_a.b~"), "user-1_a.b~");
+        assert_eq!(
+            encode_path_segment("a/b?c#d e&ü"),
+            "a%2Fb%3Fc%23d%20e%26%C3%BC"
+        );
+    }
+
+    #[test]
+    fn finale_is_the_last_episode_of_its_season() {
+        let episode = |season, number| {
+            let details = serde_json::json!({
+                "name": "Episode",
+                "airstamp": at(4, 20, 0).to_rfc3339(),
+                "season": season,
+                "number": number,
+            });
+            parse_show(1, "Tracked Show", None, details.as_object().unwrap())
+        };
+        let season_lengths = BTreeMap::from([(1, 10), (2, 8)]);
+
+        assert!(is_finale(
+            &episode(Value::from(2), Value::from(8)),
+            &season_lengths
+        ));
+        assert!(!is_finale(
+            &episode(Value::from(2), Value::from(7)),
+            &season_lengths
+        ));
+        assert!(!is_finale(
+            &episode(Value::from(3), Value::from(1)),
+            &season_lengths
+        ));
+        assert!(!is_finale(
+            &episode(Value::Null, Value::Null),
+            &season_lengths
+        ));
+
+        let mut finale = episode(Value::from(1), Value::from(10));
+        finale.finale = is_finale(&finale, &season_lengths);
+        assert!(finale
+            .html(&locale::EN)
+            .ends_with("(Episode) <b>Season Finale</b>"));
+        assert!(finale.to_string().ends_with("(Episode) - Season Finale"));
+    }
+
+    #[test]
+    fn stale_reason_requires_an_ended_show_past_the_max_age() {
+        let tracked_show = |status: &str, last_aired: Option<DateTime<Local>>| {
+            serde_json::json!({
+                "name": "Old Show",
+                "status": status,
+                "_embedded": {
+                    "previousepisode": last_aired.map(|time| serde_json::json!({ "airstamp": time.to_rfc3339() })),
+                },
+            })
+        };
+        let now = at(30, 12, 0);
+
+        assert_eq!(
+            stale_reason(&tracked_show("Ended", Some(at(1, 20, 0))), now, 14).as_deref(),
+            Some("ended, last aired 2024-03-01")
+        );
+        assert_eq!(
+            stale_reason(&tracked_show("Ended", Some(at(20, 20, 0))), now, 14),
+            None
+        );
+        assert_eq!(
+            stale_reason(&tracked_show("Running", Some(at(1, 20, 0))), now, 14),
+            None
+        );
+        assert_eq!(
+            stale_reason(&tracked_show("Ended", None), now, 14).as_deref(),
+            Some("ended, never aired")
+        );
+    }
+
+    #[test]
+    fn german_locale_translates_text_and_dates() {
+        let shows = vec![
+            show(1, "Tonight Show", "Pilot", at(4, 20, 0)),
+            show(2, "Later Show", "Finale", at(6, 21, 30)),
+        ];
+        let options = RenderOptions {
+            messages: Locale::De.messages(),
+            ..render_options()
+        };
+
+        let html = build_email_html(&digest(shows), &options);
+
+        assert!(html.starts_with("<pre><b>Heute:<br />Mo. 04. Mär. 20:00: "));
+        assert!(html.contains("Demnächst:<br />Mi. 06. Mär. 21:30: "));
+        assert!(html.contains("Abonnements verwalten auf <a href="));
+    }
+
+    /// Accepts one SMTP session on a local port, answering every command with success.
+    fn fake_smtp_relay() -> u16 {
+        use std::io::{BufRead, BufReader, Write};
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut writer = stream;
+            writer.write_all(b"220 fake ESMTP\r\n").unwrap();
+            let mut in_data = false;
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap() > 0 {
+                let reply: &[u8] = if in_data {
+                    in_data = line != ".\r\n";
+                    if in_data {
+                        b""
+                    } else {
+                        b"250 queued\r\n"
+                    }
+                } else if line.starts_with("DATA") {
+                    in_data = true;
+                    b"354 go ahead\r\n"
+                } else if line.starts_with("QUIT") {
+                    writer.write_all(b"221 bye\r\n").unwrap();
+                    break;
+                } else {
+                    b"250 ok\r\n"
+                };
+                writer.write_all(reply).unwrap();
+                line.clear();
+            }
+        });
+        port
+    }
+
+    #[test]
+    fn send_email_appends_an_audit_line_per_message() {
+        let scratch = std::env::temp_dir().join(format!("tvnotifier-audit-{}", process::id()));
+        let audit_log = scratch.join("audit.jsonl");
+        let config = Config {
+            outbox_dir: Some(scratch.join("outbox")),
+            audit_log_path: Some(audit_log.clone()),
+            ..test_config()
+        };
+        let recipients = email_recipients(vec![channel("1", "a@example.com")]);
+        let digest = digest(vec![show(1, "Tonight Show", "Pilot", at(4, 20, 0))]);
+
+        for _ in 0..2 {
+            send_email(&digest, &config, &recipients, &render_options()).unwrap();
+        }
+
+        let lines = read_audit(&audit_log);
+        fs::remove_dir_all(&scratch).unwrap();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["status"], "sent");
+        assert_eq!(lines[0]["recipients"], serde_json::json!(["a@example.com"]));
+        assert_eq!(lines[0]["subject"], "Upcoming shows for Mon. Mar. 04");
+        assert_eq!(lines[0]["shows"], 1);
+    }
+
+    #[test]
+    fn send_email_audits_a_message_that_fails_to_build() {
+        let scratch = std::env::temp_dir().join(format!("tvnotifier-audit-bad-{}", process::id()));
+        let audit_log = scratch.join("audit.jsonl");
+        let config = Config {
+            outbox_dir: Some(scratch.join("outbox")),
+            audit_log_path: Some(audit_log.clone()),
+            ..test_config()
+        };
+        let recipients = email_recipients(vec![channel("2", "not an address")]);
+        let digest = digest(vec![show(1, "Tonight Show", "Pilot", at(4, 20, 0))]);
+
+        let summary = send_email(&digest, &config, &recipients, &render_options()).unwrap();
+
+        let lines = read_audit(&audit_log);
+        fs::remove_dir_all(&scratch).unwrap();
+        assert_eq!(summary.failed.len(), 1);
+        assert_eq!(lines.len(), 1);
+        assert_eq!(lines[0]["user_id"], "2");
+        assert_eq!(lines[0]["status"], "failed");
+        assert!(lines[0]["error"]
+            .as_str()
+            .unwrap()
+            .contains("invalid email address"));
+    }
+
+    #[test]
+    fn send_email_keeps_going_past_a_failed_recipient() {
+        let outbox = std::env::temp_dir().join(format!("tvnotifier-isolated-{}", process::id()));
+        let config = Config {
+            outbox_dir: Some(outbox.clone()),
+            send_concurrency: 2,
+            ..test_config()
+        };
+        let recipients = email_recipients(vec![
+            channel("1", "a@example.com"),
+            channel("2", "not an address"),
+            channel("3", "c@example.com"),
+        ]);
+        let digest = digest(vec![show(1, "Tonight Show", "Pilot", at(4, 20, 0))]);
+
+        let summary = send_email(&digest, &config, &recipients, &render_options()).unwrap();
+
+        let written = fs::read_dir(&outbox).unwrap().count();
+        fs::remove_dir_all(&outbox).unwrap();
+        assert_eq!(summary.sent, 2);
+        assert_eq!(written, 2);
+        let failed: Vec<&str> = summary
+            .failed
+            .iter()
+            .map(|(user_id, _)| user_id.as_str())
+            .collect();
+        assert_eq!(failed, ["2"]);
+    }
+
+    #[test]
+    fn send_email_fails_over_to_the_next_relay() {
+        let unused_port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let relay = |port| SmtpRelay {
+            host: "127.0.0.1".to_owned(),
+            port: Some(port),
+            encryption: SmtpEncryption::None,
+            user: "".to_owned(),
+            password: "".to_owned(),
+        };
+        let config = Config {
+            smtp_relays: vec![relay(unused_port), relay(fake_smtp_relay())],
+            ..test_config()
+        };
+        let recipients = email_recipients(vec![channel("1", "a@example.com")]);
+        let shows = vec![show(1, "Tonight Show", "Pilot", at(4, 20, 0))];
+
+        let sent = send_email(&digest(shows), &config, &recipients, &render_options())
+            .unwrap()
+            .sent;
+
+        assert_eq!(sent, 1);
+    }
+
+    #[tokio::test]
+    async fn notify_completion_gives_up_on_a_stalled_webhook() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        // Accept the connection but never answer.
+        let stalled = std::thread::spawn(move || listener.accept().unwrap());
+        let client = build_http_client("tvnotifier-test", Duration::from_millis(200)).unwrap();
+        let result = Err(AppError::Smtp("relay down".to_owned()));
+        let started = Instant::now();
+
+        let err = notify_completion(&client, &url, &result, Duration::ZERO)
+            .await
+            .unwrap_err();
+
+        assert!(err.is_timeout(), "{err}");
+        assert!(started.elapsed() < Duration::from_secs(5));
+        drop(stalled.join().unwrap());
+    }
+
+    #[test]
+    fn send_test_email_reports_the_relay_response() {
+        let config = Config {
+            smtp_relays: vec![SmtpRelay {
+                host: "127.0.0.1".to_owned(),
+                port: Some(fake_smtp_relay()),
+                encryption: SmtpEncryption::None,
+                user: "".to_owned(),
+                password: "".to_owned(),
+            }],
+            ..test_config()
+        };
+
+        let response = send_test_email(&config, "a@example.com").unwrap();
+
+        assert_eq!(response, "relay 127.0.0.1: 250 queued");
+    }
 }
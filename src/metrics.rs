@@ -0,0 +1,110 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::future::Future;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+pub const SHOWS_FETCHED: &str = "tvnotifier_shows_fetched_total";
+pub const API_REQUESTS: &str = "tvnotifier_api_requests_total";
+pub const API_REQUEST_DURATION: &str = "tvnotifier_api_request_duration_seconds";
+pub const EMAILS_SENT: &str = "tvnotifier_emails_sent_total";
+pub const ERRORS: &str = "tvnotifier_errors_total";
+
+/// Name, Prometheus type and help text for every metric, in exposition order.
+const DESCRIPTIONS: &[(&str, &str, &str)] = &[
+    (
+        SHOWS_FETCHED,
+        "counter",
+        "Shows with an upcoming episode fetched from TVMaze.",
+    ),
+    (API_REQUESTS, "counter", "Calls made to each upstream."),
+    (
+        API_REQUEST_DURATION,
+        "summary",
+        "Time spent waiting on each upstream.",
+    ),
+    (EMAILS_SENT, "counter", "Digest emails handed to the relay."),
+    (
+        ERRORS,
+        "counter",
+        "Failed runs, show fetches and user sends by error category.",
+    ),
+];
+
+/// Sample values keyed by sample name and rendered label set.
+static REGISTRY: Mutex<BTreeMap<(String, String), f64>> = Mutex::new(BTreeMap::new());
+
+fn add_sample(name: &str, labels: &[(&str, &str)], value: f64) {
+    let labels = labels
+        .iter()
+        .map(|(key, value)| format!("{key}=\"{value}\""))
+        .collect::<Vec<_>>()
+        .join(",");
+    let mut registry = REGISTRY.lock().unwrap();
+    *registry.entry((name.to_owned(), labels)).or_default() += value;
+}
+
+pub fn add(name: &str, labels: &[(&str, &str)], value: u64) {
+    add_sample(name, labels, value as f64);
+}
+
+pub fn increment(name: &str, labels: &[(&str, &str)]) {
+    add(name, labels, 1);
+}
+
+/// Records one observation of a summary metric as its `_sum` and `_count` samples.
+pub fn observe(name: &str, labels: &[(&str, &str)], duration: Duration) {
+    add_sample(&format!("{name}_sum"), labels, duration.as_secs_f64());
+    add_sample(&format!("{name}_count"), labels, 1.0);
+}
+
+/// Awaits a call to `upstream`, counting it and recording how long it took.
+pub async fn track<T>(upstream: &str, request: impl Future<Output = T>) -> T {
+    let started = Instant::now();
+    let output = request.await;
+    record_call(upstream, started.elapsed());
+    output
+}
+
+/// Counts a call to `upstream` that took `elapsed`.
+pub fn record_call(upstream: &str, elapsed: Duration) {
+    increment(API_REQUESTS, &[("upstream", upstream)]);
+    observe(API_REQUEST_DURATION, &[("upstream", upstream)], elapsed);
+}
+
+/// Renders every recorded sample in the Prometheus text exposition format.
+pub fn render() -> String {
+    let registry = REGISTRY.lock().unwrap();
+    let mut output = String::new();
+    for (name, kind, help) in DESCRIPTIONS {
+        let _ = writeln!(output, "# HELP {name} {help}");
+        let _ = writeln!(output, "# TYPE {name} {kind}");
+        for ((sample, labels), value) in registry.iter() {
+            let suffix = sample.strip_prefix(name);
+            if !matches!(suffix, Some("" | "_sum" | "_count")) {
+                continue;
+            }
+            if labels.is_empty() {
+                let _ = writeln!(output, "{sample} {value}");
+            } else {
+                let _ = writeln!(output, "{sample}{{{labels}}} {value}");
+            }
+        }
+    }
+    output
+}
+
+/// Pushes the current samples to a Prometheus Pushgateway under the `tvnotifier` job.
+pub async fn push(pushgateway_url: &str) -> Result<(), reqwest::Error> {
+    let url = format!(
+        "{}/metrics/job/tvnotifier",
+        pushgateway_url.trim_end_matches('/')
+    );
+    crate::http_client()
+        .post(url)
+        .body(render())
+        .send()
+        .await?
+        .error_for_status()?;
+    Ok(())
+}
@@ -0,0 +1,109 @@
+use crate::error::AppError;
+use crate::{metrics, run, Config, RunOptions};
+use hyper::header::{AUTHORIZATION, CONTENT_TYPE};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use serde_json::{json, Value};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+const DEFAULT_SERVE_ADDRESS: &str = "127.0.0.1:8080";
+
+struct State {
+    config: Config,
+    options: RunOptions,
+    secret: String,
+    // Held for the duration of a run so overlapping requests can't double-send the digest.
+    run_lock: Mutex<()>,
+}
+
+/// Serves `GET /health`, `GET /metrics` and `POST /run` until the process is stopped.
+///
+/// `POST /run` requires an `Authorization: Bearer <serve_secret>` header and accepts an optional
+/// `?nomail` query to fetch without sending.
+pub async fn serve(config: Config, options: RunOptions) -> Result<(), AppError> {
+    let secret = config.serve_secret.clone().ok_or_else(|| {
+        AppError::Config("serve_secret must be set in the config to use --serve".to_owned())
+    })?;
+    let address = config
+        .serve_address
+        .as_deref()
+        .unwrap_or(DEFAULT_SERVE_ADDRESS);
+    let address: SocketAddr = address
+        .parse()
+        .map_err(|err| AppError::Config(format!("invalid serve_address {address:?}: {err}")))?;
+    let state = Arc::new(State {
+        config,
+        options,
+        secret,
+        run_lock: Mutex::new(()),
+    });
+
+    let make_service = make_service_fn(move |_| {
+        let state = state.clone();
+        async move { Ok::<_, Infallible>(service_fn(move |req| handle(state.clone(), req))) }
+    });
+    let server = Server::try_bind(&address)
+        .map_err(|err| AppError::Config(format!("cannot listen on {address}: {err}")))?
+        .serve(make_service);
+    println!("listening on http://{address}");
+    server
+        .await
+        .map_err(|err| AppError::Http(format!("server failed: {err}")))
+}
+
+async fn handle(state: Arc<State>, req: Request<Body>) -> Result<Response<Body>, Infallible> {
+    let response = match (req.method(), req.uri().path()) {
+        (&Method::GET, "/health") => json_response(StatusCode::OK, json!({ "status": "ok" })),
+        (&Method::GET, "/metrics") => Response::builder()
+            .header(CONTENT_TYPE, "text/plain; version=0.0.4")
+            .body(Body::from(metrics::render()))
+            .expect("valid response"),
+        (&Method::POST, "/run") => {
+            if !authorized(&req, &state.secret) {
+                return Ok(json_response(
+                    StatusCode::UNAUTHORIZED,
+                    json!({ "error": "unauthorized" }),
+                ));
+            }
+            let mut options = state.options;
+            if let Some(query) = req.uri().query() {
+                options.no_mail |= query.split('&').any(|param| param == "nomail");
+            }
+            let _guard = state.run_lock.lock().await;
+            match run(&state.config, options).await {
+                Ok(summary) => json_response(StatusCode::OK, json!(summary)),
+                Err(err) => json_response(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    json!({ "error": err.to_string() }),
+                ),
+            }
+        }
+        _ => json_response(StatusCode::NOT_FOUND, json!({ "error": "not found" })),
+    };
+    Ok(response)
+}
+
+fn authorized(req: &Request<Body>, secret: &str) -> bool {
+    req.headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|token| constant_time_eq(token.as_bytes(), secret.as_bytes()))
+}
+
+/// Compares in time that depends only on the lengths, so a caller can't find the secret a byte
+/// at a time.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+fn json_response(status: StatusCode, body: Value) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(body.to_string()))
+        .expect("valid response")
+}
@@ -0,0 +1,451 @@
+use crate::error::AppError;
+use crate::{metrics, Config};
+use async_trait::async_trait;
+use chrono::{DateTime, Local};
+use openssl::ssl::{SslConnector, SslMethod};
+use postgres_openssl::MakeTlsConnector;
+use std::future::Future;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+const SHOW_IDS_QUERY: &str = "SELECT id FROM shows";
+const ADD_SHOW_QUERY: &str = "INSERT INTO shows (id) VALUES ($1) ON CONFLICT DO NOTHING";
+const REMOVE_SHOW_QUERY: &str = "DELETE FROM shows WHERE id = $1";
+const NOTIFICATION_CHANNELS_QUERY: &str = "
+    SELECT c.user_id, c.channel_type, c.destination, u.name
+    FROM notification_channels c JOIN users u ON u.id = c.user_id WHERE c.enabled
+    UNION ALL
+    SELECT id, 'email', email, name FROM users
+    WHERE email IS NOT NULL AND id NOT IN (SELECT user_id FROM notification_channels)";
+const CHANNELS_TABLE_EXISTS_QUERY: &str = "
+    SELECT EXISTS (SELECT 1 FROM information_schema.tables
+    WHERE table_schema = current_schema() AND table_name = 'notification_channels')";
+const USER_NAME_COLUMN_EXISTS_QUERY: &str = "
+    SELECT EXISTS (SELECT 1 FROM information_schema.columns
+    WHERE table_schema = current_schema() AND table_name = 'users' AND column_name = 'name')";
+
+const DIGEST_HISTORY_QUERY: &str =
+    "SELECT show_id, show_name, episode_name, airstamp FROM digest_history";
+const CLEAR_DIGEST_HISTORY_QUERY: &str = "DELETE FROM digest_history";
+const SAVE_DIGEST_ENTRY_QUERY: &str = "INSERT INTO digest_history (show_id, show_name, episode_name, airstamp) VALUES ($1, $2, $3, $4)";
+
+const SQLITE_SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS shows (
+        id            INTEGER NOT NULL PRIMARY KEY
+    );
+    CREATE TABLE IF NOT EXISTS users (
+        id            TEXT NOT NULL PRIMARY KEY,
+        email         TEXT,
+        name          TEXT
+    );
+    CREATE TABLE IF NOT EXISTS notification_channels (
+        user_id       TEXT NOT NULL REFERENCES users (id),
+        channel_type  TEXT NOT NULL,
+        destination   TEXT NOT NULL,
+        enabled       BOOLEAN NOT NULL DEFAULT TRUE,
+        PRIMARY KEY (user_id, channel_type, destination)
+    );
+    CREATE TABLE IF NOT EXISTS digest_history (
+        show_id       INTEGER NOT NULL,
+        show_name     TEXT NOT NULL,
+        episode_name  TEXT NOT NULL,
+        airstamp      TEXT NOT NULL
+    );";
+
+/// A user's opt-in to receive the digest at `destination` over `channel_type` (e.g. "email").
+pub struct NotificationChannel {
+    pub user_id: String,
+    pub channel_type: String,
+    pub destination: String,
+    /// The user's display name, if they set one.
+    pub user_name: Option<String>,
+}
+
+/// One episode listed in the last digest that was sent.
+pub struct DigestEntry {
+    pub show_id: i32,
+    pub show_name: String,
+    pub episode_name: String,
+    pub show_time: DateTime<Local>,
+}
+
+impl DigestEntry {
+    fn from_row(
+        show_id: i32,
+        show_name: String,
+        episode_name: String,
+        airstamp: &str,
+    ) -> Result<Self, AppError> {
+        let show_time = DateTime::parse_from_rfc3339(airstamp).map_err(|err| {
+            AppError::Parse(format!("bad airstamp {airstamp:?} in history: {err}"))
+        })?;
+        Ok(DigestEntry {
+            show_id,
+            show_name,
+            episode_name,
+            show_time: show_time.with_timezone(&Local),
+        })
+    }
+}
+
+/// Where the tracked shows and subscribers live.
+#[async_trait]
+pub trait Store: Send + Sync {
+    async fn get_show_ids(&self) -> Result<Vec<i32>, AppError>;
+
+    /// Starts tracking `show_id`, returning false if it was already tracked.
+    async fn add_show(&self, show_id: i32) -> Result<bool, AppError>;
+
+    /// Stops tracking `show_id`, returning false if it wasn't tracked.
+    async fn remove_show(&self, show_id: i32) -> Result<bool, AppError>;
+
+    /// Loads every enabled channel, treating `users.email` as the email channel of users who
+    /// have no rows in `notification_channels` yet.
+    async fn get_notification_channels(&self) -> Result<Vec<NotificationChannel>, AppError>;
+
+    /// Loads the episodes of the last digest saved with `save_digest`.
+    async fn get_last_digest(&self) -> Result<Vec<DigestEntry>, AppError>;
+
+    /// Replaces the saved digest with `entries`.
+    async fn save_digest(&self, entries: &[DigestEntry]) -> Result<(), AppError>;
+}
+
+/// Opens the store named by `database_url` (falling back to `pg_connection_string`).
+///
+/// `sqlite:<path>` URLs select SQLite; anything else is handed to Postgres, with connecting and
+/// each query bounded by `db_timeout_secs`.
+pub async fn connect(config: &Config) -> Result<Box<dyn Store>, AppError> {
+    let url = config
+        .database_url
+        .as_deref()
+        .unwrap_or(&config.pg_connection_string);
+    match url.strip_prefix("sqlite:") {
+        Some(path) => Ok(Box::new(SqliteStore::open(path.trim_start_matches("//"))?)),
+        None => Ok(Box::new(
+            PostgresStore::connect(url, Duration::from_secs(config.db_timeout_secs)).await?,
+        )),
+    }
+}
+
+pub struct PostgresStore {
+    client: tokio_postgres::Client,
+    timeout: Duration,
+}
+
+impl PostgresStore {
+    pub async fn connect(pg_connection_string: &str, timeout: Duration) -> Result<Self, AppError> {
+        let builder = SslConnector::builder(SslMethod::tls())?;
+        let connector = MakeTlsConnector::new(builder.build());
+
+        let (client, connection) = with_timeout(
+            timeout,
+            "connecting",
+            tokio_postgres::connect(pg_connection_string, connector),
+        )
+        .await?;
+
+        // The connection object performs the actual communication with the database,
+        // so spawn it off to run on its own.
+        tokio::spawn(connection);
+
+        Ok(PostgresStore { client, timeout })
+    }
+
+    /// Tracks a query and fails it once the store's timeout has passed.
+    async fn run<T>(
+        &self,
+        query: impl Future<Output = Result<T, tokio_postgres::Error>>,
+    ) -> Result<T, AppError> {
+        with_timeout(self.timeout, "query", metrics::track("postgres", query)).await
+    }
+
+    /// Fails with a config error naming `migration` unless `exists_query` returns true.
+    ///
+    /// Postgres schemas are migrated by hand, so this turns a missing table or column into an
+    /// error that says which file to apply.
+    async fn require_schema(&self, exists_query: &str, migration: &str) -> Result<(), AppError> {
+        let exists: bool = self
+            .run(self.client.query_one(exists_query, &[]))
+            .await?
+            .get(0);
+        if exists {
+            Ok(())
+        } else {
+            Err(AppError::Config(format!(
+                "the database schema is out of date, run {migration}"
+            )))
+        }
+    }
+
+    /// Clears the saved digest and inserts `entries`; called inside a transaction.
+    async fn replace_digest(&self, entries: &[DigestEntry]) -> Result<(), AppError> {
+        self.run(self.client.execute(CLEAR_DIGEST_HISTORY_QUERY, &[]))
+            .await?;
+        for entry in entries {
+            let airstamp = entry.show_time.to_rfc3339();
+            self.run(self.client.execute(
+                SAVE_DIGEST_ENTRY_QUERY,
+                &[
+                    &entry.show_id,
+                    &entry.show_name,
+                    &entry.episode_name,
+                    &airstamp,
+                ],
+            ))
+            .await?;
+        }
+        Ok(())
+    }
+}
+
+async fn with_timeout<T>(
+    timeout: Duration,
+    what: &str,
+    future: impl Future<Output = Result<T, tokio_postgres::Error>>,
+) -> Result<T, AppError> {
+    match tokio::time::timeout(timeout, future).await {
+        Ok(result) => Ok(result?),
+        Err(_) => Err(AppError::Database(format!(
+            "{what} timed out after {}s",
+            timeout.as_secs_f64()
+        ))
+        .transient()),
+    }
+}
+
+#[async_trait]
+impl Store for PostgresStore {
+    async fn get_show_ids(&self) -> Result<Vec<i32>, AppError> {
+        let ids: Vec<i32> = self
+            .run(self.client.query(SHOW_IDS_QUERY, &[]))
+            .await?
+            .into_iter()
+            .map(|row| row.get(0))
+            .collect();
+        Ok(ids)
+    }
+
+    async fn add_show(&self, show_id: i32) -> Result<bool, AppError> {
+        let added = self
+            .run(self.client.execute(ADD_SHOW_QUERY, &[&show_id]))
+            .await?;
+        Ok(added > 0)
+    }
+
+    async fn remove_show(&self, show_id: i32) -> Result<bool, AppError> {
+        let removed = self
+            .run(self.client.execute(REMOVE_SHOW_QUERY, &[&show_id]))
+            .await?;
+        Ok(removed > 0)
+    }
+
+    async fn get_notification_channels(&self) -> Result<Vec<NotificationChannel>, AppError> {
+        self.require_schema(
+            CHANNELS_TABLE_EXISTS_QUERY,
+            "database/create_table_notification_channels.sql",
+        )
+        .await?;
+        self.require_schema(
+            USER_NAME_COLUMN_EXISTS_QUERY,
+            "database/alter_table_users_add_name.sql",
+        )
+        .await?;
+        let channels: Vec<NotificationChannel> = self
+            .run(self.client.query(NOTIFICATION_CHANNELS_QUERY, &[]))
+            .await?
+            .into_iter()
+            .map(|row| NotificationChannel {
+                user_id: row.get(0),
+                channel_type: row.get(1),
+                destination: row.get(2),
+                user_name: row.get(3),
+            })
+            .collect();
+        Ok(channels)
+    }
+
+    async fn get_last_digest(&self) -> Result<Vec<DigestEntry>, AppError> {
+        self.run(self.client.query(DIGEST_HISTORY_QUERY, &[]))
+            .await?
+            .into_iter()
+            .map(|row| DigestEntry::from_row(row.get(0), row.get(1), row.get(2), row.get(3)))
+            .collect()
+    }
+
+    async fn save_digest(&self, entries: &[DigestEntry]) -> Result<(), AppError> {
+        // The client is shared, so the transaction is driven by hand rather than through
+        // `Client::transaction`, which needs exclusive access.
+        self.run(self.client.batch_execute("BEGIN")).await?;
+        let result = self.replace_digest(entries).await;
+        let end = if result.is_ok() { "COMMIT" } else { "ROLLBACK" };
+        let ended = self.run(self.client.batch_execute(end)).await;
+        result.and(ended)
+    }
+}
+
+pub struct SqliteStore {
+    connection: Mutex<rusqlite::Connection>,
+}
+
+impl SqliteStore {
+    /// Opens (creating if needed) the database at `path` and makes sure the tables exist.
+    pub fn open(path: &str) -> Result<Self, AppError> {
+        let connection = rusqlite::Connection::open(path)?;
+        connection.execute_batch(SQLITE_SCHEMA)?;
+        // Databases created before users had a display name.
+        let has_name: bool = connection.query_row(
+            "SELECT COUNT(*) > 0 FROM pragma_table_info('users') WHERE name = 'name'",
+            [],
+            |row| row.get(0),
+        )?;
+        if !has_name {
+            connection.execute_batch("ALTER TABLE users ADD COLUMN name TEXT")?;
+        }
+        Ok(SqliteStore {
+            connection: Mutex::new(connection),
+        })
+    }
+
+    fn query<T>(
+        &self,
+        sql: &str,
+        map_row: impl FnMut(&rusqlite::Row) -> rusqlite::Result<T>,
+    ) -> Result<Vec<T>, AppError> {
+        let started = Instant::now();
+        let connection = self.connection.lock().unwrap();
+        let rows = connection
+            .prepare(sql)?
+            .query_map([], map_row)?
+            .collect::<Result<Vec<T>, _>>();
+        metrics::record_call("sqlite", started.elapsed());
+        Ok(rows?)
+    }
+
+    fn execute(&self, sql: &str, params: impl rusqlite::Params) -> Result<usize, AppError> {
+        let started = Instant::now();
+        let result = self.connection.lock().unwrap().execute(sql, params);
+        metrics::record_call("sqlite", started.elapsed());
+        Ok(result?)
+    }
+}
+
+#[async_trait]
+impl Store for SqliteStore {
+    async fn get_show_ids(&self) -> Result<Vec<i32>, AppError> {
+        self.query(SHOW_IDS_QUERY, |row| row.get(0))
+    }
+
+    async fn add_show(&self, show_id: i32) -> Result<bool, AppError> {
+        Ok(self.execute(ADD_SHOW_QUERY, [show_id])? > 0)
+    }
+
+    async fn remove_show(&self, show_id: i32) -> Result<bool, AppError> {
+        Ok(self.execute(REMOVE_SHOW_QUERY, [show_id])? > 0)
+    }
+
+    async fn get_notification_channels(&self) -> Result<Vec<NotificationChannel>, AppError> {
+        self.query(NOTIFICATION_CHANNELS_QUERY, |row| {
+            Ok(NotificationChannel {
+                user_id: row.get(0)?,
+                channel_type: row.get(1)?,
+                destination: row.get(2)?,
+                user_name: row.get(3)?,
+            })
+        })
+    }
+
+    async fn get_last_digest(&self) -> Result<Vec<DigestEntry>, AppError> {
+        self.query(DIGEST_HISTORY_QUERY, |row| {
+            Ok((
+                row.get(0)?,
+                row.get(1)?,
+                row.get(2)?,
+                row.get::<_, String>(3)?,
+            ))
+        })?
+        .into_iter()
+        .map(|(show_id, show_name, episode_name, airstamp)| {
+            DigestEntry::from_row(show_id, show_name, episode_name, &airstamp)
+        })
+        .collect()
+    }
+
+    async fn save_digest(&self, entries: &[DigestEntry]) -> Result<(), AppError> {
+        let started = Instant::now();
+        let mut connection = self.connection.lock().unwrap();
+        let result = (|| {
+            let transaction = connection.transaction()?;
+            transaction.execute(CLEAR_DIGEST_HISTORY_QUERY, [])?;
+            for entry in entries {
+                transaction.execute(
+                    SAVE_DIGEST_ENTRY_QUERY,
+                    rusqlite::params![
+                        entry.show_id,
+                        entry.show_name,
+                        entry.episode_name,
+                        entry.show_time.to_rfc3339()
+                    ],
+                )?;
+            }
+            transaction.commit()
+        })();
+        metrics::record_call("sqlite", started.elapsed());
+        Ok(result?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn postgres_connect_to_unroutable_address_times_out() {
+        let started = Instant::now();
+
+        let result = PostgresStore::connect(
+            "host=10.255.255.1 port=26257 user=tvnotifier",
+            Duration::from_millis(300),
+        )
+        .await;
+
+        // Depending on the network, this is either our timeout or an immediate refusal.
+        let err = result.err().unwrap();
+        assert_eq!(err.category(), "database");
+        assert!(err.is_transient());
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[tokio::test]
+    async fn failed_save_digest_keeps_the_previous_history() {
+        let store = SqliteStore::open(":memory:").unwrap();
+        let entry = |episode_name: &str| DigestEntry {
+            show_id: 1,
+            show_name: "Show".to_owned(),
+            episode_name: episode_name.to_owned(),
+            show_time: Local::now(),
+        };
+        store.save_digest(&[entry("Pilot")]).await.unwrap();
+        store
+            .connection
+            .lock()
+            .unwrap()
+            .execute_batch(
+                "CREATE TRIGGER fail_insert BEFORE INSERT ON digest_history
+                 WHEN NEW.episode_name = 'Broken'
+                 BEGIN SELECT RAISE(ABORT, 'insert failed'); END;",
+            )
+            .unwrap();
+
+        let result = store.save_digest(&[entry("Second"), entry("Broken")]).await;
+
+        assert!(result.is_err());
+        let saved: Vec<String> = store
+            .get_last_digest()
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.episode_name)
+            .collect();
+        assert_eq!(saved, ["Pilot"]);
+    }
+}